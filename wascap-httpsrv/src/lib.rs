@@ -19,38 +19,136 @@ extern crate wascap_codec;
 extern crate log;
 
 use actix_multipart::{Field, Multipart, MultipartError};
-use actix_web::dev::Body;
+use actix_web::dev::{Body, Server};
+use actix_web::error::BlockingError;
 use actix_web::http::StatusCode;
 use actix_web::{middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use bytes::Bytes;
 use futures::{Future, Stream};
+use net2::unix::UnixTcpBuilderExt;
+use net2::TcpBuilder;
 use prost::Message;
 use std::collections::HashMap;
 use std::error::Error as StdError;
+use std::net::TcpListener;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::thread::JoinHandle;
 use wascap_codec as codec;
 use wascap_codec::capabilities::{CapabilityProvider, Dispatcher, NullDispatcher};
 use wascap_codec::core::{Command, Event};
 use wascap_codec::AsCommand;
 
 const CAPABILITY_ID: &'static str = "wascap:http_server";
+const ENV_PORT: &'static str = "PORT";
 
 capability_provider!(HttpServerProvider, HttpServerProvider::new);
 
+/// A dispatch that returns a future rather than blocking until the guest replies. The
+/// host hands one over through `__capability_provider_async_dispatch`; this definition
+/// must match the host's
+pub type AsyncDispatch = Arc<
+    dyn Fn(&Command) -> Box<dyn Future<Item = Event, Error = std::io::Error> + Send> + Send + Sync,
+>;
+
+/// Called by the host after it configures the provider's dispatcher, handing over a
+/// dispatch that doesn't block the server's event loop
+#[no_mangle]
+pub fn __capability_provider_async_dispatch(
+    provider: &dyn CapabilityProvider,
+    dispatch: AsyncDispatch,
+) {
+    // The host only passes in providers created by this library
+    let provider =
+        unsafe { &*(provider as *const dyn CapabilityProvider as *const HttpServerProvider) };
+    *provider.async_dispatch.write().unwrap() = Some(dispatch);
+}
+
+/// Called by hosts that hand their providers settings, in place of the constructor
+/// `capability_provider!` exports. Settings the host leaves out are read from the
+/// environment
+#[no_mangle]
+pub fn __capability_provider_create_with_settings(
+    settings: &HashMap<String, String>,
+) -> *mut dyn CapabilityProvider {
+    let provider: Box<dyn CapabilityProvider> =
+        Box::new(HttpServerProvider::with_settings(settings));
+    Box::into_raw(provider)
+}
+
+/// The host's stop hook, called before it drops the provider and unloads this library.
+/// Returns `true` once the server and the thread running it have exited
+#[no_mangle]
+pub fn __capability_provider_stop(provider: &dyn CapabilityProvider) -> bool {
+    // The host only passes in providers created by this library
+    let provider =
+        unsafe { &*(provider as *const dyn CapabilityProvider as *const HttpServerProvider) };
+    provider.stop()
+}
+
+/// The running server, the actix system it runs in, and the thread running that system
+struct RunningServer {
+    server: Server,
+    system: actix_rt::System,
+    thread: JoinHandle<()>,
+}
+
 pub struct HttpServerProvider {
     dispatcher: Arc<RwLock<Box<Dispatcher>>>,
+    async_dispatch: Arc<RwLock<Option<AsyncDispatch>>>,
+    module_id: Arc<RwLock<codec::capabilities::ModuleIdentity>>,
+    port: String,
+    server: Mutex<Option<RunningServer>>,
 }
 
 impl HttpServerProvider {
     pub fn new() -> Self {
+        Self::with_settings(&HashMap::new())
+    }
+
+    pub fn with_settings(settings: &HashMap<String, String>) -> Self {
         env_logger::init();
         HttpServerProvider {
             dispatcher: Arc::new(RwLock::new(Box::new(NullDispatcher::new()))),
+            async_dispatch: Arc::new(RwLock::new(None)),
+            module_id: Arc::new(RwLock::new(codec::capabilities::ModuleIdentity {
+                issuer: "".to_string(),
+                module_name: "".to_string(),
+                capabilities: vec![],
+            })),
+            port: settings
+                .get(ENV_PORT)
+                .cloned()
+                .or_else(|| std::env::var(ENV_PORT).ok())
+                .unwrap_or("8080".to_string()),
+            server: Mutex::new(None),
+        }
+    }
+
+    /// Stops the server, waiting for its workers to finish the requests they're handling
+    /// and for the server's thread to exit. Returns `false` if that thread panicked
+    fn stop(&self) -> bool {
+        let running = self.server.lock().unwrap().take();
+        match running {
+            Some(running) => {
+                info!("Stopping HTTP server");
+                let _ = running.server.stop(true).wait();
+                running.system.stop();
+                *self.async_dispatch.write().unwrap() = None;
+                running.thread.join().is_ok()
+            }
+            None => true,
         }
     }
 }
 
+impl Drop for HttpServerProvider {
+    fn drop(&mut self) {
+        // Does nothing if the host has already called the stop hook
+        self.stop();
+    }
+}
+
 impl CapabilityProvider for HttpServerProvider {
     fn capability_id(&self) -> &'static str {
         CAPABILITY_ID
@@ -65,25 +163,54 @@ impl CapabilityProvider for HttpServerProvider {
 
         let mut lock = self.dispatcher.write().unwrap();
         *lock = dispatcher;
+        *self.module_id.write().unwrap() = module_id;
+
+        // The host configures a running provider again when the module's identity
+        // changes; the server picks up the new dispatcher and identity as it is
+        if self.server.lock().unwrap().is_some() {
+            return Ok(());
+        }
 
         let disp = self.dispatcher.clone();
+        let async_disp = self.async_dispatch.clone();
+        let module_id = self.module_id.clone();
+        let (server_s, server_r) = crossbeam_channel::bounded(1);
+        info!("Starting HTTP server on port {}", self.port);
+        let listener = listen(&self.port)?;
 
-        std::thread::spawn(move || {
-            HttpServer::new(move || {
+        let thread = std::thread::spawn(move || {
+            let sys = actix_rt::System::new("wascap-httpsrv");
+            let server = HttpServer::new(move || {
                 App::new()
                     .wrap(middleware::Logger::default())
                     .data(disp.clone())
+                    .data(async_disp.clone())
                     .data(module_id.clone())
                     .service(web::resource("/healthz").to(health_check))
                     .service(web::resource("/id").to(show_claims))
                     .service(web::resource("/liveupdate").route(web::post().to_async(upload)))
-                    .default_service(web::route().to(request_handler))
+                    .default_service(web::route().to_async(request_handler))
             })
-            .bind("0.0.0.0:8080")
-            .unwrap()
-            .disable_signals()
-            .run()
-            .unwrap();
+            .listen(listener)
+            .map(|server| server.disable_signals().start());
+
+            let started = server.is_ok();
+            let _ = server_s.send(server.map(|server| (server, actix_rt::System::current())));
+            if started {
+                let _ = sys.run();
+            }
+        });
+
+        let (server, system) = match server_r.recv() {
+            Ok(Ok(running)) => running,
+            Ok(Err(e)) => return Err(Box::new(e)),
+            Err(_) => return Err("HTTP server thread exited before starting".into()),
+        };
+        // Held so the server can be stopped when this provider is unloaded
+        *self.server.lock().unwrap() = Some(RunningServer {
+            server,
+            system,
+            thread,
         });
 
         Ok(())
@@ -121,27 +248,74 @@ fn health_check(state: web::Data<Arc<RwLock<Box<Dispatcher>>>>) -> HttpResponse
                     .body(event.error.map_or("".to_string(), |e| e.description))
             }
         }
-        Err(e) => HttpResponse::InternalServerError().body(format!("{}", e)),
+        Err(e) => dispatch_error_response(to_io_error(e)),
+    }
+}
+
+/// Converts a dispatch error into one that can cross threads, keeping the kind of
+/// I/O errors reported by the host
+fn to_io_error(e: Box<dyn StdError>) -> std::io::Error {
+    let kind = e
+        .downcast_ref::<std::io::Error>()
+        .map_or(std::io::ErrorKind::Other, |e| e.kind());
+    std::io::Error::new(kind, format!("{}", e))
+}
+
+/// Maps a failed dispatch to a response, reporting a guest that didn't reply in time
+/// as a gateway timeout and a full host queue as unavailable
+fn dispatch_error_response(e: std::io::Error) -> HttpResponse {
+    match e.kind() {
+        std::io::ErrorKind::TimedOut => HttpResponse::GatewayTimeout().body(format!("{}", e)),
+        std::io::ErrorKind::WouldBlock => {
+            HttpResponse::ServiceUnavailable().body(format!("{}", e))
+        }
+        _ => HttpResponse::InternalServerError().body(format!("{}", e)),
+    }
+}
+
+/// Maps a failure event from the guest module (or the host acting on its behalf) to a
+/// response
+fn failure_response(event: Event) -> HttpResponse {
+    let (code, description) = event
+        .error
+        .map_or((500, "".to_string()), |e| (e.code, e.description));
+    match code {
+        403 => HttpResponse::Forbidden().body(description),
+        503 => HttpResponse::ServiceUnavailable().body(description),
+        504 => HttpResponse::GatewayTimeout().body(description),
+        _ => HttpResponse::InternalServerError().body(description),
     }
 }
 
 fn show_claims(
-    state: web::Data<codec::capabilities::ModuleIdentity>,
+    state: web::Data<Arc<RwLock<codec::capabilities::ModuleIdentity>>>,
     req: HttpRequest,
 ) -> HttpResponse {
-    HttpResponse::Ok().json(state.get_ref())
+    HttpResponse::Ok().json(&*state.read().unwrap())
 }
 
+/// Submits each uploaded file to the guest module as a live update, answering with the
+/// sizes of the files or, if the host rejected one, the reason it was rejected
 fn upload(
     multipart: Multipart,
     state: web::Data<Arc<RwLock<Box<Dispatcher>>>>,
+    async_state: web::Data<Arc<RwLock<Option<AsyncDispatch>>>>,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
     multipart
         .map_err(actix_web::error::ErrorInternalServerError)
-        .map(move |field| save_file(field, &state).into_stream())
+        .map(move |field| save_file(field, &state, &async_state).into_stream())
         .flatten()
         .collect()
-        .map(|sizes| HttpResponse::Ok().json(sizes))
+        .map(|results| {
+            let mut sizes = Vec::new();
+            for result in results {
+                match result {
+                    Ok(size) => sizes.push(size),
+                    Err(response) => return response,
+                }
+            }
+            HttpResponse::Ok().json(sizes)
+        })
         .map_err(|e| {
             println!("failed: {}", e);
             e
@@ -151,8 +325,10 @@ fn upload(
 fn save_file(
     field: Field,
     state: &web::Data<Arc<RwLock<Box<Dispatcher>>>>,
-) -> impl Future<Item = i64, Error = Error> {
+    async_state: &web::Data<Arc<RwLock<Option<AsyncDispatch>>>>,
+) -> impl Future<Item = Result<i64, HttpResponse>, Error = Error> {
     let ns = state.clone();
+    let async_ns = async_state.clone();
     field
         .fold(
             (Vec::<u8>::new(), 0i64),
@@ -172,32 +348,57 @@ fn save_file(
                 })
             },
         )
-        .map(move |(buf, acc)| {
-            dispatch_module(&buf, &ns);
-            acc
-        })
         .map_err(|e| {
             println!("save_file failed, {:?}", e);
             actix_web::error::ErrorInternalServerError(e)
         })
+        .and_then(move |(buf, acc)| {
+            dispatch_module(buf, &ns, &async_ns).map(move |result| result.map(|_| acc))
+        })
 }
 
-fn dispatch_module(newmodule: &[u8], state: &web::Data<Arc<RwLock<Box<Dispatcher>>>>) {
+/// Sends the new module to the host, resolving to the response for the uploader if the
+/// update failed or was rejected
+fn dispatch_module(
+    newmodule: Vec<u8>,
+    state: &web::Data<Arc<RwLock<Box<dyn Dispatcher>>>>,
+    async_state: &web::Data<Arc<RwLock<Option<AsyncDispatch>>>>,
+) -> impl Future<Item = Result<(), HttpResponse>, Error = Error> {
     let update = codec::core::LiveUpdate {
-        new_module: newmodule.to_vec(),
+        new_module: newmodule,
     };
     let cmd = update.as_command("wascap:http_server", "guest");
-    let _evt = {
-        let lock = (*state).read().unwrap();
-        lock.dispatch(&cmd).unwrap()
-    };
+
+    dispatch(cmd, state, async_state).then(|res| -> Result<Result<(), HttpResponse>, Error> {
+        Ok(match res {
+            Ok(ref evt) if evt.success => Ok(()),
+            Ok(evt) => Err(failure_response(evt)),
+            Err(BlockingError::Error(e)) => Err(dispatch_error_response(e)),
+            Err(BlockingError::Canceled) => {
+                Err(HttpResponse::ServiceUnavailable().body("Live update dispatch was canceled"))
+            }
+        })
+    })
+}
+
+/// Listens on the port with `SO_REUSEPORT` set, so that when the host reloads this
+/// provider the new server can start listening before the old one stops
+fn listen(port: &str) -> Result<TcpListener, Box<dyn StdError>> {
+    let builder = TcpBuilder::new_v4()?;
+    builder.reuse_address(true)?;
+    builder.reuse_port(true)?;
+    builder.bind(format!("0.0.0.0:{}", port))?;
+    Ok(builder.listen(1024)?)
 }
 
+/// Dispatches the request to the guest module without holding up the server's event
+/// loop while the guest handles it
 fn request_handler(
     req: HttpRequest,
     payload: Bytes,
     state: web::Data<Arc<RwLock<Box<Dispatcher>>>>,
-) -> HttpResponse {
+    async_state: web::Data<Arc<RwLock<Option<AsyncDispatch>>>>,
+) -> impl Future<Item = HttpResponse, Error = Error> {
     let request = codec::http::Request {
         method: req.method().as_str().to_string(),
         path: req.uri().path().to_string(),
@@ -207,10 +408,41 @@ fn request_handler(
     };
     let cmd = request.as_command("wascap:http_server", "guest");
 
-    let evt = {
-        let lock = (*state).read().unwrap();
-        lock.dispatch(&cmd).unwrap()
-    };
+    dispatch(cmd, &state, &async_state).then(|res| -> Result<HttpResponse, Error> {
+        Ok(match res {
+            Ok(evt) => event_response(evt),
+            Err(BlockingError::Error(e)) => dispatch_error_response(e),
+            Err(BlockingError::Canceled) => {
+                HttpResponse::ServiceUnavailable().body("Request dispatch was canceled")
+            }
+        })
+    })
+}
+
+/// Sends the command to the guest module through the host's asynchronous dispatch, or,
+/// if the host didn't provide one, through the blocking dispatcher on the thread pool
+fn dispatch(
+    cmd: Command,
+    state: &web::Data<Arc<RwLock<Box<dyn Dispatcher>>>>,
+    async_state: &web::Data<Arc<RwLock<Option<AsyncDispatch>>>>,
+) -> Box<dyn Future<Item = Event, Error = BlockingError<std::io::Error>>> {
+    let async_dispatch = async_state.read().unwrap().clone();
+    match async_dispatch {
+        Some(dispatch) => Box::new(dispatch(&cmd).map_err(BlockingError::Error)),
+        None => {
+            let disp = state.get_ref().clone();
+            Box::new(web::block(move || {
+                let lock = disp.read().unwrap();
+                lock.dispatch(&cmd).map_err(to_io_error)
+            }))
+        }
+    }
+}
+
+fn event_response(evt: Event) -> HttpResponse {
+    if !evt.success {
+        return failure_response(evt);
+    }
     let r = codec::http::Response::decode(evt.payload.unwrap().value).unwrap();
 
     HttpResponse::with_body(
@@ -19,9 +19,13 @@ extern crate wascap_codec;
 extern crate log;
 
 use natsclient as nats;
+use std::collections::HashMap;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
 use wascap_codec as codec;
 use wascap_codec::capabilities::{CapabilityProvider, Dispatcher, NullDispatcher};
 use wascap_codec::core::{Command, Event};
@@ -32,21 +36,51 @@ use wascap_codec::AsCommand;
 
 capability_provider!(NatsProvider, NatsProvider::new);
 
+/// Called by hosts that hand their providers settings, in place of the constructor
+/// `capability_provider!` exports. Settings the host leaves out are read from the
+/// environment
+#[no_mangle]
+pub fn __capability_provider_create_with_settings(
+    settings: &HashMap<String, String>,
+) -> *mut dyn CapabilityProvider {
+    let provider: Box<dyn CapabilityProvider> = Box::new(NatsProvider::with_settings(settings));
+    Box::into_raw(provider)
+}
+
+/// The host's stop hook, called before it drops the provider. The NATS client's own
+/// reader and callback threads can't be joined, so this always returns `false`, telling
+/// the host to keep this library loaded rather than unload code those threads run
+#[no_mangle]
+pub fn __capability_provider_stop(provider: &dyn CapabilityProvider) -> bool {
+    // The host only passes in providers created by this library
+    let provider = unsafe { &*(provider as *const dyn CapabilityProvider as *const NatsProvider) };
+    provider.stop();
+    false
+}
+
 const ENV_NATS_SUBSCRIPTION: &'static str = "NATS_SUBSCRIPTION";
 const ENV_NATS_URL: &'static str = "NATS_URL";
 
 pub struct NatsProvider {
     dispatcher: Arc<RwLock<Box<Dispatcher>>>,
     client: nats::Client,
+    subscribe_to: Option<String>,
+    subscription: RwLock<Option<String>>,
+    stopped: Arc<AtomicBool>,
+    delivering: Arc<AtomicUsize>,
 }
 
 impl NatsProvider {
     pub fn new() -> NatsProvider {
+        NatsProvider::with_settings(&HashMap::new())
+    }
+
+    pub fn with_settings(settings: &HashMap<String, String>) -> NatsProvider {
         env_logger::init();
 
-        let nats_url = match std::env::var(ENV_NATS_URL) {
-            Ok(v) => v,
-            Err(_) => "nats://localhost:4222".to_string(),
+        let nats_url = match setting(settings, ENV_NATS_URL) {
+            Some(v) => v,
+            None => "nats://localhost:4222".to_string(),
         };
 
         info!("Attempting to establish NATS connection to URL: {}", nats_url);
@@ -62,6 +96,29 @@ impl NatsProvider {
         NatsProvider {
             dispatcher: Arc::new(RwLock::new(Box::new(NullDispatcher::new()))),
             client: c,
+            subscribe_to: setting(settings, ENV_NATS_SUBSCRIPTION),
+            subscription: RwLock::new(None),
+            stopped: Arc::new(AtomicBool::new(false)),
+            delivering: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Stops delivering messages to the guest, unsubscribes, and waits for messages
+    /// already being delivered
+    fn stop(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        *self.dispatcher.write().unwrap() = Box::new(NullDispatcher::new());
+
+        if let Some(ref sub) = *self.subscription.read().unwrap() {
+            info!("Unsubscribing from '{}'", sub);
+            if let Err(e) = self.client.unsubscribe(sub) {
+                error!("Failed to unsubscribe from '{}': {}", sub, e);
+            }
+        }
+        while self.delivering.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(10));
         }
     }
 
@@ -105,6 +162,27 @@ impl NatsProvider {
     }
 }
 
+/// A setting from the host, or from the environment if the host didn't provide it
+fn setting(settings: &HashMap<String, String>, key: &str) -> Option<String> {
+    settings
+        .get(key)
+        .cloned()
+        .or_else(|| std::env::var(key).ok())
+}
+
+/// Whether a dispatch failed because the host's one-way queue for this capability is full
+fn is_queue_full(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .map_or(false, |e| e.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+impl Drop for NatsProvider {
+    fn drop(&mut self) {
+        // Does nothing if the host has already called the stop hook
+        self.stop();
+    }
+}
+
 impl CapabilityProvider for NatsProvider {
     fn capability_id(&self) -> &'static str {
         "wascap:messaging"
@@ -119,13 +197,28 @@ impl CapabilityProvider for NatsProvider {
         let mut lock = self.dispatcher.write().unwrap();
         *lock = dispatcher;
 
+        // Configured again by the host when the module's identity changes, in which case
+        // the existing subscription delivers to the new dispatcher
+        if self.subscription.read().unwrap().is_some() {
+            return Ok(());
+        }
+
         let disp = self.dispatcher.clone();
+        let stopped = self.stopped.clone();
+        let delivering = self.delivering.clone();
 
-        match std::env::var(ENV_NATS_SUBSCRIPTION) {
-            Ok(ref sub) => {
+        match self.subscribe_to {
+            Some(ref sub) => {
                 info!("Subscribing to '{}'", sub);
                 self.client
                     .subscribe(&sub, move |msg| {
+                        // Counted before checking for a stop, so `stop` either sees this
+                        // delivery or this delivery sees the stop
+                        delivering.fetch_add(1, Ordering::SeqCst);
+                        if stopped.load(Ordering::SeqCst) {
+                            delivering.fetch_sub(1, Ordering::SeqCst);
+                            return Ok(());
+                        }
                         let dm = DeliverMessage {
                             message: Some(BrokerMessage {
                                 subject: msg.subject.clone(),
@@ -134,17 +227,28 @@ impl CapabilityProvider for NatsProvider {
                             }),
                         };
 
-                        let _evt = {
+                        // When the host makes this capability one-way, dispatch returns as
+                        // soon as the message is queued, so the subscription isn't held up
+                        // while the guest handles it
+                        let evt = {
                             let d = disp.read().unwrap();
                             d.dispatch(&dm.as_command("wascap:messaging", "guest"))
-                                .unwrap();
                         };
+                        if let Err(e) = evt {
+                            if is_queue_full(e.as_ref()) {
+                                warn!("Dropping message on '{}': {}", msg.subject, e);
+                            } else {
+                                error!("Failed to deliver message to guest: {}", e);
+                            }
+                        }
+                        delivering.fetch_sub(1, Ordering::SeqCst);
 
                         Ok(())                
                     })
                     .unwrap();
+                *self.subscription.write().unwrap() = Some(sub.to_string());
             },
-            Err(_) => {},
+            None => {},
         };
         
         Ok(())
@@ -24,6 +24,7 @@ use keyvalue::{
 };
 use prost::Message;
 use redis::{self, Commands};
+use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
 use std::sync::RwLock;
@@ -37,6 +38,28 @@ const ENV_REDIS_URL: &'static str = "REDIS_URL";
 
 capability_provider!(RedisKVProvider, RedisKVProvider::new);
 
+/// Called by hosts that hand their providers settings, in place of the constructor
+/// `capability_provider!` exports. Settings the host leaves out are read from the
+/// environment
+#[no_mangle]
+pub fn __capability_provider_create_with_settings(
+    settings: &HashMap<String, String>,
+) -> *mut dyn CapabilityProvider {
+    let provider: Box<dyn CapabilityProvider> = Box::new(RedisKVProvider::with_settings(settings));
+    Box::into_raw(provider)
+}
+
+/// The host's stop hook, called before it drops the provider and unloads this library.
+/// The provider starts no threads, so it's always safe to unload
+#[no_mangle]
+pub fn __capability_provider_stop(provider: &dyn CapabilityProvider) -> bool {
+    // The host only passes in providers created by this library
+    let provider =
+        unsafe { &*(provider as *const dyn CapabilityProvider as *const RedisKVProvider) };
+    provider.stop();
+    true
+}
+
 pub struct RedisKVProvider {
     dispatcher: Arc<RwLock<Box<Dispatcher>>>,
     client: redis::Client,
@@ -44,11 +67,15 @@ pub struct RedisKVProvider {
 
 impl RedisKVProvider {
     pub fn new() -> Self {
+        Self::with_settings(&HashMap::new())
+    }
+
+    pub fn with_settings(settings: &HashMap<String, String>) -> Self {
         env_logger::init();
 
-        let redis_url = match std::env::var(ENV_REDIS_URL) {
-            Ok(v) => v,
-            Err(_) => "redis://127.0.0.1/".to_string(),
+        let redis_url = match setting(settings, ENV_REDIS_URL) {
+            Some(v) => v,
+            None => "redis://127.0.0.1/".to_string(),
         };
 
         let client = redis::Client::open(redis_url.as_ref()).unwrap(); // TODO: get from dispatch options
@@ -128,6 +155,26 @@ impl RedisKVProvider {
             ..Default::default()
         })
     }
+
+    fn stop(&self) {
+        // Connections are opened per call, so dropping the client is all that's left
+        info!("Stopping Redis key-value provider");
+        *self.dispatcher.write().unwrap() = Box::new(NullDispatcher::new());
+    }
+}
+
+/// A setting from the host, or from the environment if the host didn't provide it
+fn setting(settings: &HashMap<String, String>, key: &str) -> Option<String> {
+    settings
+        .get(key)
+        .cloned()
+        .or_else(|| std::env::var(key).ok())
+}
+
+impl Drop for RedisKVProvider {
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
 
 impl CapabilityProvider for RedisKVProvider {
@@ -12,18 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use quicli::prelude::*;
 use reqwest::StatusCode;
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGINT, SIGTERM, SIGUSR1};
 
 use std::fs::{read_dir, File};
 use std::io::Read;
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use wascap::jwt::validate_token;
 use wascap::jwt::Claims;
-use waxosuit_host::wasm::ModuleHost;
-use waxosuit_host::capabilities::CAPMAN;
-
+use waxosuit_host::canary::CanaryOptions;
+use waxosuit_host::capabilities::CapabilityManager;
+use waxosuit_host::dispatch::{OverflowPolicy, QueueOptions};
+use waxosuit_host::mux::MuxOptions;
+use waxosuit_host::trust::PinnedIssuers;
+use waxosuit_host::wasm::{GuestModule, ModuleCache, ModuleHost};
 
 #[derive(Debug, StructOpt, Clone)]
 #[structopt(
@@ -32,14 +40,59 @@ use waxosuit_host::capabilities::CAPMAN;
     about = "A WASCAP host runtime process for executing WebAssembly modules with secure capability bindings"
 )]
 struct Cli {
-    /// Input file
-    #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    /// Signed WebAssembly module files. Each module gets its own claims, instances,
+    /// multiplexer and capability providers
+    #[structopt(parse(from_os_str), raw(required = "true"))]
+    inputs: Vec<PathBuf>,
+
+    /// Setting handed to one module's capability providers, e.g. `hello.wasm:PORT=8081`
+    /// (may be repeated). Provider processes receive it as an environment variable
+    #[structopt(long = "module-env", parse(try_from_str = "parse_module_env"))]
+    module_envs: Vec<(String, String, String)>,
 
     /// Directory from which to load capability providers
     #[structopt(parse(from_os_str), short = "c", long = "caps")]
     caps_dir: PathBuf,
 
+    /// Watch the capabilities directory and reload providers when their libraries change
+    #[structopt(short = "w", long = "watch")]
+    watch_caps: bool,
+
+    /// Capability provider executable to run as a child process (may be repeated)
+    #[structopt(parse(from_os_str), short = "r", long = "remote")]
+    remote_caps: Vec<PathBuf>,
+
+    /// Maximum time, in milliseconds, a host call to a provider process, or one of its
+    /// dispatches to the module, waits for a reply
+    #[structopt(
+        long = "remote-timeout",
+        default_value = "30000",
+        env = "REMOTE_CALL_TIMEOUT"
+    )]
+    remote_timeout_ms: u64,
+
+    /// Issuer, other than the running module's own, whose signed modules are accepted as
+    /// live updates (may be repeated)
+    #[structopt(long = "update-issuer")]
+    update_issuers: Vec<String>,
+
+    /// Public key of an account whose modules may run, optionally grouped under an operator
+    /// key so it's only trusted while that operator is, e.g. `<account key>:<operator key>`
+    /// (may be repeated). Keys are pinned, not verified against account or operator JWTs.
+    /// When no keys are given, modules from any issuer run
+    #[structopt(long = "trusted-issuer", parse(try_from_str = "parse_trusted_issuer"))]
+    trusted_issuers: Vec<(String, Option<String>)>,
+
+    /// Public key of an operator whose grouped accounts, and directly signed modules, may
+    /// run (may be repeated)
+    #[structopt(long = "trusted-operator")]
+    trusted_operators: Vec<String>,
+
+    /// File of pinned keys, one per line: an operator key, an account key, or an account
+    /// key followed by its operator's key
+    #[structopt(parse(from_os_str), long = "trust-file", env = "TRUST_FILE")]
+    trust_file: Option<PathBuf>,
+
     /// URL to POST a WebAssembly module's JWT for Open Policy Agent evaluation
     #[structopt(short = "o", long = "opa", env = "OPA_URL")]
     opa_url: Option<String>,
@@ -51,13 +104,176 @@ struct Cli {
     /// Used to indicate the sink URL when using a waxosuit module as a knative event emitter
     #[structopt(short = "s", long = "sink", env = "SINK")]
     sink: Option<String>,
+
+    /// The number of WebAssembly module instances in the pool used to handle dispatched commands
+    #[structopt(
+        short = "i",
+        long = "instances",
+        default_value = "1",
+        env = "INSTANCES"
+    )]
+    instances: usize,
+
+    /// Directory in which compiled WebAssembly modules are cached, keyed by module hash.
+    /// Cached modules run as native code, so the directory must only be writable by the host
+    #[structopt(parse(from_os_str), long = "cache", env = "MODULE_CACHE")]
+    cache_dir: Option<PathBuf>,
+
+    /// File holding the secret used to authenticate cached modules. When not given, a key
+    /// is generated in the cache directory
+    #[structopt(
+        parse(from_os_str),
+        long = "cache-key-file",
+        env = "MODULE_CACHE_KEY_FILE"
+    )]
+    cache_key_file: Option<PathBuf>,
+
+    /// Maximum time, in milliseconds, that a single call into the WebAssembly module may take.
+    /// Live updates are limited by --live-update-timeout instead. Any call timeout, including
+    /// --cap-timeout, has the module metered so that calls can be stopped in guest code
+    #[structopt(short = "t", long = "timeout", env = "CALL_TIMEOUT")]
+    timeout_ms: Option<u64>,
+
+    /// Maximum time, in seconds, to compile and health check a live update before failing it.
+    /// A live update that times out is not installed
+    #[structopt(
+        long = "live-update-timeout",
+        default_value = "300",
+        env = "LIVE_UPDATE_TIMEOUT"
+    )]
+    live_update_timeout_secs: u64,
+
+    /// Per-capability call timeout overrides, e.g. `wascap:messaging=30000`
+    #[structopt(long = "cap-timeout", parse(try_from_str = "parse_cap_timeout"))]
+    cap_timeouts: Vec<(String, u64)>,
+
+    /// Per-capability scheduling weights, e.g. `wascap:http_server=10`. When several
+    /// capabilities have commands waiting, each gets the module in proportion to its weight
+    #[structopt(long = "cap-weight", parse(try_from_str = "parse_cap_weight"))]
+    cap_weights: Vec<(String, u32)>,
+
+    /// Maximum time, in milliseconds, a capability provider waits for a reply to a dispatch.
+    /// Live updates are limited by --live-update-timeout instead
+    #[structopt(short = "d", long = "dispatch-timeout", env = "DISPATCH_TIMEOUT")]
+    dispatch_timeout_ms: Option<u64>,
+
+    /// Capabilities whose dispatches are queued for the module without waiting for a reply,
+    /// e.g. `wascap:messaging`
+    #[structopt(long = "one-way")]
+    one_way_caps: Vec<String>,
+
+    /// Maximum number of one-way dispatches each capability may have queued at once
+    #[structopt(long = "one-way-depth", env = "ONE_WAY_DEPTH")]
+    one_way_depth: Option<usize>,
+
+    /// Maximum number of commands each capability may have waiting for the module
+    #[structopt(long = "queue-capacity", env = "QUEUE_CAPACITY")]
+    queue_capacity: Option<usize>,
+
+    /// Per-capability queue capacity overrides, e.g. `wascap:messaging=1000`
+    #[structopt(long = "cap-queue", parse(try_from_str = "parse_cap_queue"))]
+    cap_queues: Vec<(String, usize)>,
+
+    /// What to do with a command when its capability's queue is full: block, drop-newest,
+    /// drop-oldest or reject
+    #[structopt(long = "queue-policy", default_value = "block", env = "QUEUE_POLICY")]
+    queue_policy: OverflowPolicy,
+
+    /// Seconds between reports of each capability's queue depth
+    #[structopt(long = "queue-report", env = "QUEUE_REPORT_INTERVAL")]
+    queue_report_secs: Option<u64>,
+
+    /// Maximum number of 64KiB pages of linear memory each WebAssembly module instance may use.
+    /// Modules must declare a maximum memory size no larger than this
+    #[structopt(short = "m", long = "max-memory-pages", env = "MAX_MEMORY_PAGES")]
+    max_memory_pages: Option<u32>,
+
+    /// Number of previous module versions kept so a live update can be rolled back
+    #[structopt(long = "history", default_value = "3", env = "MODULE_HISTORY")]
+    history: usize,
+
+    /// Seconds a live-updated module has to pass a health check before it is rolled back
+    /// to the previous version
+    #[structopt(long = "health-grace", env = "HEALTH_GRACE")]
+    health_grace_secs: Option<u64>,
+
+    /// Percentage of commands sent to a live update while it runs as a canary alongside
+    /// the current version. Live updates replace the module outright when no canary
+    /// options are given
+    #[structopt(long = "canary", env = "CANARY_PERCENT")]
+    canary_percent: Option<u32>,
+
+    /// Per-capability canary percentages, e.g. `wascap:messaging=50`
+    #[structopt(long = "canary-cap", parse(try_from_str = "parse_cap_percent"))]
+    canary_caps: Vec<(String, u32)>,
+
+    /// HTTP header that sends a request to the canary whenever it's present
+    #[structopt(long = "canary-header", env = "CANARY_HEADER")]
+    canary_header: Option<String>,
+
+    /// Number of calls a canary handles before it's promoted or aborted
+    #[structopt(long = "canary-calls", default_value = "100", env = "CANARY_CALLS")]
+    canary_calls: usize,
+
+    /// Highest fraction of failed canary calls at which the canary is still promoted
+    #[structopt(
+        long = "canary-max-failures",
+        default_value = "0.05",
+        env = "CANARY_MAX_FAILURES"
+    )]
+    canary_max_failures: f64,
+
+    /// Seconds to wait for in-flight calls to finish when shutting down
+    #[structopt(
+        short = "g",
+        long = "grace",
+        default_value = "30",
+        env = "GRACE_PERIOD"
+    )]
+    grace_secs: u64,
+}
+
+/// Exit status when in-flight calls were still running at the end of the grace period
+const EXIT_DRAIN_TIMEOUT: i32 = 1;
+
+/// Prefix of the claims tag a module can use to request a lower memory limit than
+/// the host's `--max-memory-pages`
+const MEMORY_LIMIT_TAG: &'static str = "max_memory_pages=";
+
+/// A module read from disk whose embedded token has been validated
+struct SignedModule {
+    path: PathBuf,
+    claims: Claims,
+    buf: Vec<u8>,
 }
 
 fn main() -> Result<(), Box<dyn ::std::error::Error>> {
     let args = Cli::from_args();
-    let inputfile = &args.input;
     env_logger::init();
+    // The HTTP server provider reads its port from the environment
+    std::env::set_var("PORT", args.port.to_string());
+
+    let trusted = trusted_issuers(&args)?;
+    let mut modules = Vec::new();
+    for inputfile in args.inputs.iter() {
+        match read_module(&args, &trusted, inputfile)? {
+            Some(module) => modules.push(module),
+            // The reason has already been reported
+            None => std::process::exit(1),
+        }
+    }
+
+    let status = start(&args, &trusted, modules).unwrap();
+    std::process::exit(status);
+}
 
+/// Reads a module and validates its embedded token, returning `None` if the module
+/// can't be used
+fn read_module(
+    args: &Cli,
+    trusted: &PinnedIssuers,
+    inputfile: &PathBuf,
+) -> Result<Option<SignedModule>, Box<dyn ::std::error::Error>> {
     let buf = {
         let mut wfile = File::open(inputfile).unwrap();
         let mut buf = Vec::new();
@@ -75,22 +291,28 @@ fn main() -> Result<(), Box<dyn ::std::error::Error>> {
                     "Will not load WebAssembly module. Token is currently unusable. It will be usable {}",
                     validate_res.not_before_human
                 );
-                Ok(())
+                Ok(None)
             } else if validate_res.expired {
                 eprint!(
                     "Will not load WebAssembly module. Token expired {}",
                     validate_res.expires_human
                 );
-                Ok(())
+                Ok(None)
+            } else if let Err(e) = trusted.check(&token.claims.issuer) {
+                eprint!("Will not load WebAssembly module. {}", e);
+                Ok(None)
             } else {
-                check_token_with_opa(&args, token.jwt)?;
-                start(&args, token.claims, buf).unwrap();
-                Ok(())
+                check_token_with_opa(&args.opa_url, &token.jwt)?;
+                Ok(Some(SignedModule {
+                    path: inputfile.clone(),
+                    claims: token.claims,
+                    buf,
+                }))
             }
         }
         Ok(None) => {
             eprint!("No capability signature found");
-            Ok(())
+            Ok(None)
         }
         Err(e) => {
             eprint!("Error reading capabilities from file: {}", e);
@@ -99,36 +321,372 @@ fn main() -> Result<(), Box<dyn ::std::error::Error>> {
     }
 }
 
-fn start(args: &Cli, claims: Claims, buf: Vec<u8>) -> waxosuit_host::Result<()> {
+/// Runs the modules until the process receives SIGINT or SIGTERM, then shuts down
+/// gracefully and returns the process exit status
+fn start(
+    args: &Cli,
+    trusted: &PinnedIssuers,
+    modules: Vec<SignedModule>,
+) -> waxosuit_host::Result<i32> {
+    let mut managers = Vec::new();
+    for module in modules {
+        managers.push(host_module(args, trusted, module)?);
+    }
+    let managers = Arc::new(managers);
+
+    let _watcher = if args.watch_caps {
+        Some(watch_capabilities(&args.caps_dir, managers.clone())?)
+    } else {
+        None
+    };
+
+    if let Some(secs) = args.queue_report_secs {
+        report_queues(Duration::from_secs(secs), managers.clone());
+    }
+
+    let signals = Signals::new(&[SIGINT, SIGTERM, SIGUSR1])?;
+    for signal in signals.forever() {
+        if signal == SIGUSR1 {
+            rollback(&managers);
+        } else {
+            info!("Received signal {}, shutting down", signal);
+            break;
+        }
+    }
+
+    shutdown(&managers, Duration::from_secs(args.grace_secs))
+}
+
+/// Loads a module's capability providers into a capability manager of its own and
+/// starts its multiplexer
+fn host_module(
+    args: &Cli,
+    trusted: &PinnedIssuers,
+    module: SignedModule,
+) -> waxosuit_host::Result<HostedModule> {
+    let SignedModule { path, claims, buf } = module;
+    let module_name = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let capman = Arc::new(RwLock::new(CapabilityManager::new()));
     {
-        let mut capman = CAPMAN.write().unwrap();
+        let mut capman = capman.write().unwrap();
         capman.set_claims(claims.clone());
+        capman.set_update_issuers(args.update_issuers.clone());
+        capman.set_pinned_issuers(trusted.clone());
+        capman.set_provider_settings(module_env(args, &path));
+        if args.opa_url.is_some() {
+            // Live updates are checked with OPA just as the module was at startup
+            let opa_url = args.opa_url.clone();
+            capman.set_token_check(Arc::new(move |jwt: &str| {
+                check_token_with_opa(&opa_url, jwt)
+            }));
+        }
+        capman.set_dispatch_timeout(args.dispatch_timeout_ms.map(Duration::from_millis));
+        capman.set_remote_call_timeout(Duration::from_millis(args.remote_timeout_ms));
+        for capid in args.one_way_caps.iter() {
+            capman.set_one_way(capid.as_str(), args.one_way_depth);
+        }
+        capman.set_default_queue(QueueOptions {
+            capacity: args.queue_capacity,
+            policy: args.queue_policy,
+        });
+        for (capid, capacity) in args.cap_queues.iter() {
+            capman.set_queue(
+                capid.as_str(),
+                QueueOptions {
+                    capacity: Some(*capacity),
+                    policy: args.queue_policy,
+                },
+            );
+        }
     }
-    add_capabilities(&args.caps_dir, &claims.caps);
-
-    let module_name: &str = args.input.file_stem().unwrap_or_default().to_str().unwrap();
+    add_capabilities(&capman, &args.caps_dir);
+    add_remote_capabilities(&capman, &args.remote_caps);
 
     info!(
         "Starting Waxosuit for module {} with capability claims - {}",
         module_name,
-        claims.caps.map_or("none".to_string(), |c| c.join(", "))
+        claims
+            .caps
+            .as_ref()
+            .map_or("none".to_string(), |c| c.join(", "))
     );
 
+    let options = mux_options(args);
+    let metered = options.has_call_timeout();
+    let module = match args.cache_dir {
+        Some(ref dir) => {
+            let cache = ModuleCache::open(dir, args.cache_key_file.as_ref().map(|p| p.as_path()))?;
+            GuestModule::with_cache(&buf, &cache, &claims.module_hash, metered)?
+        }
+        None => GuestModule::new(&buf, metered)?,
+    };
+    let module = Arc::new(
+        module
+            .limit_memory(memory_limit(args, &claims))
+            .bind_capabilities(capman.clone())
+            .with_claims(claims)
+            .keep_history(args.history)
+            .check_health(args.health_grace_secs.map(Duration::from_secs))
+            .with_canary(canary_options(args)),
+    );
     {
-        let lock = CAPMAN.read().unwrap();
-        lock.start_mux(move || {
-            let host = ModuleHost::new(&buf).unwrap();
+        let lock = capman.read().unwrap();
+        let module = module.clone();
+        lock.start_mux(options, move || {
+            let host = ModuleHost::from_module(module.clone())?;
             Ok(host)
         })?;
     }
 
-    std::thread::park();
-    Ok(())
+    Ok(HostedModule {
+        name: module_name,
+        module,
+        capman,
+    })
 }
 
-fn check_token_with_opa(args: &Cli, jwt: String) -> waxosuit_host::Result<()> {
-    args.opa_url.as_ref().map_or(Ok(()), |url| {
-        let postresult = post_json(url, &jwt)?;
+/// A running module and the capability manager holding its claims and providers
+struct HostedModule {
+    name: String,
+    module: Arc<GuestModule>,
+    capman: Arc<RwLock<CapabilityManager>>,
+}
+
+/// The module's `--module-env` settings, which are handed to its capability providers
+fn module_env(args: &Cli, path: &PathBuf) -> Vec<(String, String)> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    args.module_envs
+        .iter()
+        .filter(|(module, _, _)| *module == file_name)
+        .map(|(_, key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Rolls every module back to the version that was live before its last update
+fn rollback(modules: &[HostedModule]) {
+    for hosted in modules {
+        match hosted.module.rollback() {
+            Ok(generation) => {
+                info!(
+                    "Rolled back module {}, now at generation {}",
+                    hosted.name, generation
+                );
+                for version in hosted.module.history() {
+                    info!(
+                        "  generation {} - hash {}, issuer {}, live since {:?}",
+                        version.generation, version.hash, version.issuer, version.timestamp
+                    );
+                }
+            }
+            Err(e) => warn!("Could not roll back module {}: {}", hosted.name, e),
+        }
+    }
+}
+
+/// Periodically logs how many commands are waiting in each capability's queue
+fn report_queues(interval: Duration, modules: Arc<Vec<HostedModule>>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        for module in modules.iter() {
+            let status = module.capman.read().unwrap().queue_status();
+            for (capid, queue) in status.iter() {
+                info!(
+                    "Queue for {} ({}): {} waiting (capacity {}), {} overflowed",
+                    capid,
+                    module.name,
+                    queue.depth,
+                    queue
+                        .capacity
+                        .map_or("unbounded".to_string(), |c| c.to_string()),
+                    queue.overflowed
+                );
+            }
+        }
+    });
+}
+
+/// Stops accepting new dispatches, drains in-flight guest calls and then stops every
+/// capability provider, for every module. The modules share the grace period. A module
+/// that doesn't drain in time still has its multiplexer stopped, but its providers that
+/// are waiting on the guest are left running until the process exits
+fn shutdown(modules: &[HostedModule], grace: Duration) -> waxosuit_host::Result<i32> {
+    let deadline = Instant::now() + grace;
+    let mut drained = Vec::new();
+
+    for module in modules {
+        let now = Instant::now();
+        let remaining = if now < deadline {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        };
+        let module_drained = {
+            let lock = module.capman.read().unwrap();
+            lock.drain_mux(remaining)
+        };
+        if module_drained {
+            // Nothing is left in flight, so the workers will exit promptly
+            module.capman.read().unwrap().stop_mux();
+        } else {
+            warn!(
+                "Module {} did not drain before the grace period ran out",
+                module.name
+            );
+            module.capman.read().unwrap().halt_mux();
+        }
+        drained.push(module_drained);
+    }
+    for (module, module_drained) in modules.iter().zip(drained.iter()) {
+        let mut capman = module.capman.write().unwrap();
+        if *module_drained {
+            capman.unload();
+            continue;
+        }
+        for capid in capman.unload_idle() {
+            warn!(
+                "Provider for {} still has dispatches in flight for module {}, leaving it running",
+                capid, module.name
+            );
+        }
+    }
+
+    if drained.iter().all(|d| *d) {
+        info!("Shutdown complete");
+        Ok(0)
+    } else {
+        Ok(EXIT_DRAIN_TIMEOUT)
+    }
+}
+
+/// Collects the pinned keys given on the command line and in the trust file
+fn trusted_issuers(args: &Cli) -> waxosuit_host::Result<PinnedIssuers> {
+    let mut trusted = PinnedIssuers::new();
+    for (account, operator) in args.trusted_issuers.iter() {
+        trusted.pin_account(account, operator.as_ref().map(|o| o.as_str()))?;
+    }
+    for operator in args.trusted_operators.iter() {
+        trusted.pin_operator(operator)?;
+    }
+    if let Some(ref path) = args.trust_file {
+        trusted.load_file(path)?;
+    }
+    Ok(trusted)
+}
+
+/// The effective memory limit is the lower of the host's limit and the module's
+/// claimed limit, if either is present
+fn memory_limit(args: &Cli, claims: &Claims) -> Option<u32> {
+    let claimed = claims.tags.as_ref().and_then(|tags| {
+        tags.iter()
+            .filter(|t| t.starts_with(MEMORY_LIMIT_TAG))
+            .filter_map(|t| t[MEMORY_LIMIT_TAG.len()..].parse::<u32>().ok())
+            .min()
+    });
+    match (args.max_memory_pages, claimed) {
+        (Some(host), Some(claimed)) => Some(host.min(claimed)),
+        (host, claimed) => host.or(claimed),
+    }
+}
+
+/// Canary options are only used when a percentage, per-capability percentage or
+/// header is given
+fn canary_options(args: &Cli) -> Option<CanaryOptions> {
+    if args.canary_percent.is_none() && args.canary_caps.is_empty() && args.canary_header.is_none()
+    {
+        return None;
+    }
+    Some(CanaryOptions {
+        percent: args.canary_percent.unwrap_or(0),
+        capability_percents: args.canary_caps.iter().cloned().collect(),
+        header: args.canary_header.clone(),
+        min_calls: args.canary_calls,
+        max_failure_rate: args.canary_max_failures,
+    })
+}
+
+fn mux_options(args: &Cli) -> MuxOptions {
+    MuxOptions {
+        pool_size: args.instances,
+        call_timeout: args.timeout_ms.map(Duration::from_millis),
+        capability_timeouts: args
+            .cap_timeouts
+            .iter()
+            .map(|(capid, ms)| (capid.clone(), Duration::from_millis(*ms)))
+            .collect(),
+        live_update_timeout: Some(Duration::from_secs(args.live_update_timeout_secs)),
+        capability_weights: args.cap_weights.iter().cloned().collect(),
+    }
+}
+
+fn parse_module_env(s: &str) -> Result<(String, String, String), String> {
+    let invalid = || format!("Expected <module file>:<name>=<value>, got '{}'", s);
+    let mut parts = s.splitn(2, ':');
+    let (module, var) = match (parts.next(), parts.next()) {
+        (Some(module), Some(var)) if !module.is_empty() => (module, var),
+        _ => return Err(invalid()),
+    };
+    let mut var = var.splitn(2, '=');
+    match (var.next(), var.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => {
+            Ok((module.to_string(), key.to_string(), value.to_string()))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_trusted_issuer(s: &str) -> Result<(String, Option<String>), String> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(account), operator) if !account.is_empty() => {
+            Ok((account.to_string(), operator.map(|o| o.to_string())))
+        }
+        _ => Err(format!(
+            "Expected <account key>[:<operator key>], got '{}'",
+            s
+        )),
+    }
+}
+
+fn parse_cap_timeout(s: &str) -> Result<(String, u64), String> {
+    parse_cap_value(s, "milliseconds")
+}
+
+fn parse_cap_queue(s: &str) -> Result<(String, usize), String> {
+    parse_cap_value(s, "capacity")
+}
+
+fn parse_cap_weight(s: &str) -> Result<(String, u32), String> {
+    parse_cap_value(s, "weight")
+}
+
+fn parse_cap_percent(s: &str) -> Result<(String, u32), String> {
+    parse_cap_value(s, "percent")
+}
+
+/// Parses a per-capability setting of the form `<capability>=<value>`
+fn parse_cap_value<T>(s: &str, name: &str) -> Result<(String, T), String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(capid), Some(value)) if !capid.is_empty() => value
+            .parse::<T>()
+            .map(|value| (capid.to_string(), value))
+            .map_err(|e| format!("Invalid {} for {}: {}", name, capid, e)),
+        _ => Err(format!("Expected <capability>=<{}>, got '{}'", name, s)),
+    }
+}
+
+fn check_token_with_opa(opa_url: &Option<String>, jwt: &str) -> waxosuit_host::Result<()> {
+    opa_url.as_ref().map_or(Ok(()), |url| {
+        let postresult = post_json(url, jwt)?;
         let oparesult: OpaReply = serde_json::from_str(&postresult)?;
         if oparesult.allow {
             info!("OPA validation PASSED");
@@ -169,7 +727,7 @@ pub fn post_json(url: &str, token: &str) -> waxosuit_host::Result<String> {
     }
 }
 
-fn add_capabilities(caps_dir: &PathBuf, caps: &Option<Vec<String>>) {
+fn add_capabilities(capman: &RwLock<CapabilityManager>, caps_dir: &PathBuf) {
     if caps_dir.is_dir() {
         for entry in read_dir(caps_dir).unwrap() {
             let entry = entry.unwrap();
@@ -179,7 +737,7 @@ fn add_capabilities(caps_dir: &PathBuf, caps: &Option<Vec<String>>) {
                     Some("dylib") | Some("so") => {
                         let result = {
                             unsafe {
-                                let mut capman = CAPMAN.write().unwrap();
+                                let mut capman = capman.write().unwrap();
                                 capman.load_plugin(path)
                             }
                         };
@@ -201,6 +759,79 @@ fn add_capabilities(caps_dir: &PathBuf, caps: &Option<Vec<String>>) {
     }
 }
 
+/// Reloads any provider library that's added to or replaced in the capabilities directory.
+/// The watch lasts as long as the returned watcher
+fn watch_capabilities(
+    caps_dir: &PathBuf,
+    modules: Arc<Vec<HostedModule>>,
+) -> waxosuit_host::Result<RecommendedWatcher> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher: RecommendedWatcher = Watcher::new(tx, Duration::from_secs(2))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    watcher
+        .watch(caps_dir, RecursiveMode::NonRecursive)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    info!(
+        "Watching {} for capability provider changes",
+        caps_dir.display()
+    );
+
+    std::thread::spawn(move || {
+        for event in rx.iter() {
+            let path = match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path) => path,
+                _ => continue,
+            };
+            match path.extension().and_then(|ex| ex.to_str()) {
+                Some("dylib") | Some("so") => {}
+                _ => continue,
+            }
+
+            info!("Capability provider library {} changed", path.display());
+            for module in modules.iter() {
+                let result = {
+                    unsafe {
+                        let mut capman = module.capman.write().unwrap();
+                        capman.reload_plugin(&path)
+                    }
+                };
+                match result {
+                    Ok(capid) => {
+                        info!("Capability provider {} reloaded for {}", capid, module.name);
+                    }
+                    Err(e) => {
+                        error!(
+                            "Capability provider not reloaded for {}: {}",
+                            module.name, e
+                        );
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+fn add_remote_capabilities(capman: &RwLock<CapabilityManager>, programs: &[PathBuf]) {
+    for program in programs {
+        let result = {
+            let mut capman = capman.write().unwrap();
+            capman.load_remote_plugin(program)
+        };
+        match result {
+            Ok(capid) => {
+                info!("Capability provider process {} started", capid);
+            }
+            Err(e) => {
+                info!("Capability provider process not started: {}", e);
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct OpaInput {
     token: String,
@@ -209,4 +840,4 @@ struct OpaInput {
 #[derive(Serialize, Deserialize, Debug)]
 struct OpaReply {
     allow: bool,
-}
\ No newline at end of file
+}
@@ -0,0 +1,315 @@
+// Copyright 2015-2018 Capital One Services, LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wascap::jwt::Claims;
+use wascap_codec as codec;
+use wascap_codec::core::Command;
+use wasmer_runtime_core::Module;
+
+const HTTP_SERVER_CAPABILITY: &'static str = "wascap:http_server";
+
+/// How dispatched commands are split between the running module and a live update
+/// being trialled alongside it, and when the trial ends
+#[derive(Debug, Clone)]
+pub struct CanaryOptions {
+    /// Percentage of commands handled by the new version
+    pub percent: u32,
+    /// Per-capability overrides of `percent`
+    pub capability_percents: HashMap<String, u32>,
+    /// HTTP requests carrying this header are always handled by the new version
+    pub header: Option<String>,
+    /// Number of calls the new version handles before it is promoted or aborted
+    pub min_calls: usize,
+    /// Highest fraction of failed calls at which the new version is still promoted
+    pub max_failure_rate: f64,
+}
+
+impl Default for CanaryOptions {
+    fn default() -> CanaryOptions {
+        CanaryOptions {
+            percent: 10,
+            capability_percents: HashMap::new(),
+            header: None,
+            min_calls: 100,
+            max_failure_rate: 0.05,
+        }
+    }
+}
+
+/// What to do with a canary after its latest call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Verdict {
+    Pending,
+    Promote,
+    Abort,
+}
+
+/// A live update running alongside the current module version, with the outcomes of
+/// the calls routed to it so far
+pub(crate) struct Canary {
+    pub serial: u64,
+    pub module: Module,
+    pub claims: Claims,
+    trial: Trial,
+}
+
+impl Canary {
+    pub fn new(serial: u64, module: Module, claims: Claims, options: CanaryOptions) -> Canary {
+        Canary {
+            serial,
+            module,
+            claims,
+            trial: Trial::new(options),
+        }
+    }
+
+    /// Whether the command should be handled by the new version. Commands from each
+    /// capability are spread evenly rather than at random, so that exactly the
+    /// configured share of them reaches the canary
+    pub fn routes(&self, cmd: &Command) -> bool {
+        self.trial.routes(cmd)
+    }
+
+    /// Records the outcome of a call handled by the new version and judges it once it
+    /// has handled enough calls
+    pub fn record(&self, success: bool) -> Verdict {
+        self.trial.record(success)
+    }
+
+    /// The number of calls handled by the new version, and how many of them failed
+    pub fn stats(&self) -> (usize, usize) {
+        self.trial.stats()
+    }
+}
+
+/// How commands are split between the current version and a canary, and the outcomes
+/// of the canary's calls
+struct Trial {
+    options: CanaryOptions,
+    routed: HashMap<String, AtomicUsize>,
+    default_routed: AtomicUsize,
+    calls: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl Trial {
+    fn new(options: CanaryOptions) -> Trial {
+        Trial {
+            routed: options
+                .capability_percents
+                .keys()
+                .map(|capid| (capid.clone(), AtomicUsize::new(0)))
+                .collect(),
+            options,
+            default_routed: AtomicUsize::new(0),
+            calls: AtomicUsize::new(0),
+            failures: AtomicUsize::new(0),
+        }
+    }
+
+    fn routes(&self, cmd: &Command) -> bool {
+        if let Some(ref header) = self.options.header {
+            if has_header(cmd, header) {
+                return true;
+            }
+        }
+        let (counter, percent) = match self.routed.get(&cmd.source) {
+            Some(counter) => (counter, self.options.capability_percents[&cmd.source]),
+            None => (&self.default_routed, self.options.percent),
+        };
+        let percent = percent.min(100) as usize;
+        let n = counter.fetch_add(1, Ordering::SeqCst) % 100;
+        (n + 1) * percent / 100 != n * percent / 100
+    }
+
+    fn record(&self, success: bool) -> Verdict {
+        let calls = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        let failures = if success {
+            self.failures.load(Ordering::SeqCst)
+        } else {
+            self.failures.fetch_add(1, Ordering::SeqCst) + 1
+        };
+
+        if calls < self.options.min_calls {
+            Verdict::Pending
+        } else if failures as f64 / calls as f64 > self.options.max_failure_rate {
+            Verdict::Abort
+        } else {
+            Verdict::Promote
+        }
+    }
+
+    fn stats(&self) -> (usize, usize) {
+        (
+            self.calls.load(Ordering::SeqCst),
+            self.failures.load(Ordering::SeqCst),
+        )
+    }
+}
+
+/// Header names are compared case-insensitively, as the HTTP server provider passes
+/// them along in lower case
+fn has_header(cmd: &Command, header: &str) -> bool {
+    if cmd.source != HTTP_SERVER_CAPABILITY {
+        return false;
+    }
+    cmd.payload
+        .as_ref()
+        .and_then(|p| codec::http::Request::decode(&p.value).ok())
+        .map_or(false, |req| {
+            req.header.keys().any(|k| k.eq_ignore_ascii_case(header))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wascap_codec::AsCommand;
+
+    fn options(percent: u32) -> CanaryOptions {
+        CanaryOptions {
+            percent,
+            ..Default::default()
+        }
+    }
+
+    fn command(source: &str) -> Command {
+        Command {
+            source: source.to_string(),
+            target_cap: "guest".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn http_request(headers: &[(&str, &str)]) -> Command {
+        codec::http::Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            header: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+        .as_command(HTTP_SERVER_CAPABILITY, "guest")
+    }
+
+    fn routed(trial: &Trial, cmd: &Command, n: usize) -> usize {
+        (0..n).filter(|_| trial.routes(cmd)).count()
+    }
+
+    #[test]
+    fn routes_exactly_the_configured_share() {
+        for percent in &[0, 1, 10, 33, 50, 99, 100] {
+            let trial = Trial::new(options(*percent));
+            assert_eq!(
+                routed(&trial, &command("wascap:messaging"), 300),
+                3 * *percent as usize
+            );
+        }
+    }
+
+    #[test]
+    fn spreads_routed_commands_evenly() {
+        let trial = Trial::new(options(25));
+        let cmd = command("wascap:messaging");
+        let routes: Vec<bool> = (0..8).map(|_| trial.routes(&cmd)).collect();
+        assert_eq!(
+            routes,
+            [false, false, false, true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn percentages_over_100_route_everything() {
+        let trial = Trial::new(options(250));
+        assert_eq!(routed(&trial, &command("wascap:messaging"), 50), 50);
+    }
+
+    #[test]
+    fn capability_percentages_override_the_default() {
+        let mut options = options(10);
+        options
+            .capability_percents
+            .insert("wascap:messaging".to_string(), 50);
+        let trial = Trial::new(options);
+
+        assert_eq!(routed(&trial, &command("wascap:messaging"), 100), 50);
+        assert_eq!(routed(&trial, &command("wascap:keyvalue"), 100), 10);
+    }
+
+    #[test]
+    fn header_routes_regardless_of_percentage() {
+        let mut options = options(0);
+        options.header = Some("X-Canary".to_string());
+        let trial = Trial::new(options);
+
+        assert_eq!(routed(&trial, &http_request(&[("x-canary", "1")]), 10), 10);
+        assert_eq!(routed(&trial, &http_request(&[("x-other", "1")]), 10), 0);
+        assert_eq!(routed(&trial, &http_request(&[]), 10), 0);
+    }
+
+    #[test]
+    fn header_only_applies_to_http_requests() {
+        let mut options = options(0);
+        options.header = Some("x-canary".to_string());
+        let trial = Trial::new(options);
+
+        let mut cmd = http_request(&[("x-canary", "1")]);
+        cmd.source = "wascap:messaging".to_string();
+        assert_eq!(routed(&trial, &cmd, 10), 0);
+    }
+
+    #[test]
+    fn pending_until_min_calls() {
+        let trial = Trial::new(CanaryOptions {
+            min_calls: 3,
+            ..Default::default()
+        });
+        assert_eq!(trial.record(true), Verdict::Pending);
+        assert_eq!(trial.record(false), Verdict::Pending);
+        assert_eq!(trial.stats(), (2, 1));
+    }
+
+    #[test]
+    fn promoted_within_failure_rate() {
+        let trial = Trial::new(CanaryOptions {
+            min_calls: 4,
+            max_failure_rate: 0.25,
+            ..Default::default()
+        });
+        trial.record(false);
+        trial.record(true);
+        trial.record(true);
+        assert_eq!(trial.record(true), Verdict::Promote);
+        assert_eq!(trial.stats(), (4, 1));
+    }
+
+    #[test]
+    fn aborted_above_failure_rate() {
+        let trial = Trial::new(CanaryOptions {
+            min_calls: 4,
+            max_failure_rate: 0.25,
+            ..Default::default()
+        });
+        trial.record(false);
+        trial.record(true);
+        trial.record(true);
+        assert_eq!(trial.record(false), Verdict::Abort);
+        assert_eq!(trial.stats(), (4, 2));
+    }
+}
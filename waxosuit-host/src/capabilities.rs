@@ -12,43 +12,132 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::dispatch::WaxosuitDispatcher;
+use crate::dispatch::{
+    log_dead_letter, AsyncDispatch, DeadLetterHook, QueueOptions, QueueStatus, WaxosuitDispatcher,
+};
 use crate::errors;
-use crate::mux::Multiplexer;
+use crate::mux::{failure_event, Multiplexer, MuxOptions};
+use crate::remote::{self, RemoteProvider};
+use crate::trust::PinnedIssuers;
 use crate::wasm::ModuleHost;
 use crate::Result;
-use crossbeam_channel as channel;
 use libloading::{Library, Symbol};
 use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::sync::RwLock;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
 use wascap_codec as codec;
 use wascap_codec::capabilities::{CapabilityProvider, Dispatcher, ModuleIdentity};
 use wascap_codec::core::{Command, Event};
 
+/// Where a capability provider was loaded from, so it can be loaded again if a live
+/// update claims its capability
+#[derive(Clone, Debug)]
+enum ProviderSource {
+    Library(PathBuf),
+    Remote(PathBuf),
+}
+
+/// Checks a module's signed token against host policy (such as Open Policy Agent),
+/// failing if the module may not run
+pub type TokenCheck = Arc<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+/// Log target for security-relevant decisions about capability access
+const AUDIT_TARGET: &'static str = "waxosuit::audit";
+
+static LIBRARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 lazy_static! {
-    pub static ref CAPMAN: RwLock<CapabilityManager> = { RwLock::new(CapabilityManager::new()) };
+    /// The capability manager for guest modules that aren't bound to one of their own
+    pub static ref CAPMAN: Arc<RwLock<CapabilityManager>> =
+        { Arc::new(RwLock::new(CapabilityManager::new())) };
+
+    /// The private directory holding copies of provider libraries while they're loaded,
+    /// created the first time one is needed
+    static ref LIBRARY_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
 }
 
 pub struct CapabilityManager {
     plugins: HashMap<String, Box<CapabilityProvider>>,
-    loaded_libraries: Vec<Library>,
+    loaded_libraries: HashMap<String, Library>,
+    dispatchers: HashMap<String, WaxosuitDispatcher>,
     muxer: Multiplexer,
     claims: Option<wascap::jwt::Claims>,
+    dispatch_timeout: Option<Duration>,
+    one_way: HashMap<String, Option<usize>>,
+    dead_letter: DeadLetterHook,
+    default_queue: QueueOptions,
+    queues: HashMap<String, QueueOptions>,
+    update_issuers: Vec<String>,
+    pinned_issuers: PinnedIssuers,
+    granted_caps: Vec<String>,
+    token_check: Option<TokenCheck>,
+    sources: HashMap<String, ProviderSource>,
+    provider_settings: Vec<(String, String)>,
+    remote_call_timeout: Duration,
 }
 
 impl CapabilityManager {
     pub fn new() -> CapabilityManager {
         CapabilityManager {
             plugins: HashMap::new(),
-            loaded_libraries: Vec::new(),
+            loaded_libraries: HashMap::new(),
+            dispatchers: HashMap::new(),
             muxer: Multiplexer::new(),
             claims: None,
+            dispatch_timeout: None,
+            one_way: HashMap::new(),
+            dead_letter: Arc::new(log_dead_letter),
+            default_queue: QueueOptions::default(),
+            queues: HashMap::new(),
+            update_issuers: Vec::new(),
+            pinned_issuers: PinnedIssuers::new(),
+            granted_caps: Vec::new(),
+            token_check: None,
+            sources: HashMap::new(),
+            provider_settings: Vec::new(),
+            remote_call_timeout: remote::DEFAULT_CALL_TIMEOUT,
         }
     }
 
+    /// Delivers a host call from the guest module to the target capability provider. Calls
+    /// to capabilities the module hasn't claimed, or for which no provider is loaded, are
+    /// denied with a failure event rather than reaching a provider
     pub fn call(&self, cmd: &Command) -> Result<Event> {
-        let capability = self.plugins.get(&cmd.target_cap).unwrap();
+        if !self.is_claimed(&cmd.target_cap) {
+            warn!(
+                target: AUDIT_TARGET,
+                "DENIED host call to unclaimed capability '{}' from {}",
+                cmd.target_cap,
+                self.module_subject()
+            );
+            return Ok(failure_event(
+                403,
+                format!("Capability not claimed by module: {}", cmd.target_cap),
+            ));
+        }
+
+        let capability = match self.plugins.get(&cmd.target_cap) {
+            Some(capability) => capability,
+            None => {
+                warn!(
+                    target: AUDIT_TARGET,
+                    "DENIED host call to unloaded capability '{}' from {}",
+                    cmd.target_cap,
+                    self.module_subject()
+                );
+                return Ok(failure_event(
+                    404,
+                    format!("No provider loaded for capability: {}", cmd.target_cap),
+                ));
+            }
+        };
 
         match capability.handle_call(cmd) {
             Ok(evt) => Ok(evt),
@@ -56,18 +145,254 @@ impl CapabilityManager {
         }
     }
 
-    pub fn start_mux<F>(&self, factory: F) -> Result<()>
+    pub fn start_mux<F>(&self, options: MuxOptions, factory: F) -> Result<()>
     where
         F: Fn() -> Result<ModuleHost> + Sync + Send,
         F: 'static,
     {
-        self.muxer.run(factory)
+        self.muxer.run(options, factory)
+    }
+
+    /// The dispatcher bound to a loaded capability, which host code can use to send
+    /// commands to the guest module on that capability's behalf
+    pub fn dispatcher(&self, capid: &str) -> Option<WaxosuitDispatcher> {
+        self.dispatchers.get(capid).cloned()
+    }
+
+    /// Reports how full each loaded capability's queue of commands for the guest is
+    pub fn queue_status(&self) -> HashMap<String, QueueStatus> {
+        self.dispatchers
+            .iter()
+            .map(|(capid, d)| (capid.clone(), d.queue_status()))
+            .collect()
+    }
+
+    /// The number of times the multiplexer has recovered from a failed guest module
+    /// instance or thread
+    pub fn mux_restarts(&self) -> usize {
+        self.muxer.restarts()
+    }
+
+    /// Stops the multiplexer from accepting new commands and waits up to the grace
+    /// period for in-flight guest calls to finish
+    pub fn drain_mux(&self, grace: Duration) -> bool {
+        self.muxer.drain(grace)
+    }
+
+    /// Stops the multiplexer's select loop and waits for its workers to finish the
+    /// commands they were running
+    pub fn stop_mux(&self) {
+        self.muxer.stop();
+        self.muxer.join();
+    }
+
+    /// Stops the multiplexer's select loop, failing the commands still queued, without
+    /// waiting for its workers. They exit once they've finished the guest calls they're
+    /// running
+    pub fn halt_mux(&self) {
+        self.muxer.stop();
+    }
+
+    /// Sets how long providers wait for the guest module to reply to a dispatched
+    /// command. Applies to providers loaded after it's set
+    pub fn set_dispatch_timeout(&mut self, timeout: Option<Duration>) {
+        self.dispatch_timeout = timeout
+    }
+
+    /// Makes dispatches from the capability's provider one-way: they return as soon as
+    /// the command is queued, with at most `max_pending` queued at a time. Applies to
+    /// providers loaded after it's set
+    pub fn set_one_way(&mut self, capid: impl Into<String>, max_pending: Option<usize>) {
+        self.one_way.insert(capid.into(), max_pending);
+    }
+
+    /// Sets the queue used by capabilities without queue options of their own. Applies
+    /// to providers loaded after it's set
+    pub fn set_default_queue(&mut self, options: QueueOptions) {
+        self.default_queue = options
+    }
+
+    /// Sets the queue for a single capability. Applies to providers loaded after it's set
+    pub fn set_queue(&mut self, capid: impl Into<String>, options: QueueOptions) {
+        self.queues.insert(capid.into(), options);
+    }
+
+    /// Sets the hook called when the guest module fails to handle a one-way command
+    pub fn set_dead_letter_hook(&mut self, hook: DeadLetterHook) {
+        self.dead_letter = hook
     }
 
+    /// Sets the claims of the module as it was started. The capabilities claimed here are
+    /// the ones live updates may claim without passing the token check again
     pub fn set_claims(&mut self, claims: wascap::jwt::Claims) {
+        self.granted_caps = claims.caps.as_ref().map_or(vec![], |c| c.clone());
         self.claims = Some(claims)
     }
 
+    /// Sets the check every live update's token must pass. Without one, live updates
+    /// can't claim capabilities the module wasn't started with
+    pub fn set_token_check(&mut self, check: TokenCheck) {
+        self.token_check = Some(check)
+    }
+
+    /// Allows live updates signed by these issuers, in addition to the issuer of the
+    /// running module
+    pub fn set_update_issuers(&mut self, issuers: Vec<String>) {
+        self.update_issuers = issuers
+    }
+
+    /// Restricts the issuers of live updates to these pinned keys, on top of the checks
+    /// against the running module's issuer and the update issuers
+    pub fn set_pinned_issuers(&mut self, pinned: PinnedIssuers) {
+        self.pinned_issuers = pinned
+    }
+
+    /// Checks that a module submitted as a live update carries a valid token from the
+    /// running module's issuer (or an allowed update issuer) that passes the token check,
+    /// if there is one. Without a token check, the update may claim no capabilities beyond
+    /// those the module was started with. Returns the new module's claims
+    pub fn verify_update(&self, buf: &[u8]) -> Result<wascap::jwt::Claims> {
+        let current = match self.claims {
+            Some(ref claims) => claims,
+            None => return Err(update_rejected("no claims for the running module")),
+        };
+        let token = match wascap::wasm::extract_claims(buf) {
+            Ok(Some(token)) => token,
+            Ok(None) => return Err(update_rejected("module is not signed")),
+            Err(e) => return Err(update_rejected(format!("unreadable claims: {}", e))),
+        };
+        let validation = wascap::jwt::validate_token(&token.jwt)
+            .map_err(|e| update_rejected(format!("invalid token: {}", e)))?;
+        if validation.expired {
+            return Err(update_rejected(format!(
+                "token expired {}",
+                validation.expires_human
+            )));
+        }
+        if validation.cannot_use_yet {
+            return Err(update_rejected(format!(
+                "token not usable until {}",
+                validation.not_before_human
+            )));
+        }
+
+        let claims = token.claims;
+        if claims.issuer != current.issuer && !self.update_issuers.contains(&claims.issuer) {
+            return Err(update_rejected(format!(
+                "issuer {} is not trusted for updates",
+                claims.issuer
+            )));
+        }
+        self.pinned_issuers
+            .check(&claims.issuer)
+            .map_err(|e| update_rejected(format!("{}", e)))?;
+        if let Some(ref check) = self.token_check {
+            check(&token.jwt).map_err(|e| update_rejected(format!("{}", e)))?;
+        }
+        // Capabilities beyond those granted at startup need the token check's approval
+        if self.token_check.is_none() {
+            if let Some(ref caps) = claims.caps {
+                if let Some(cap) = caps.iter().find(|c| !self.granted_caps.contains(c)) {
+                    return Err(update_rejected(format!(
+                        "capability {} was not granted to the running module",
+                        cap
+                    )));
+                }
+            }
+        }
+
+        info!(
+            target: AUDIT_TARGET,
+            "ACCEPTED live update for {} signed by {}",
+            current.subject,
+            claims.issuer
+        );
+        Ok(claims)
+    }
+
+    /// Makes the claims of a module that has just been swapped in the module's claims.
+    /// Loaded providers for capabilities it doesn't claim are unloaded, providers for
+    /// claimed capabilities that aren't loaded yet are loaded from wherever they were
+    /// found at startup, and every provider is told the module's new identity
+    pub fn apply_update(&mut self, claims: wascap::jwt::Claims) {
+        let old_identity = self.module_id_for_claims();
+        let new_caps = claims.caps.as_ref().map_or(vec![], |c| c.clone());
+        self.claims = Some(claims);
+
+        // Decided by what's actually loaded rather than by the old claims, which may not
+        // have listed every capability (a module without a capability list may use any)
+        let dropped: Vec<String> = self
+            .plugins
+            .keys()
+            .filter(|capid| self.check_claimed(capid).is_err())
+            .cloned()
+            .collect();
+        // The update arrives as a call on the executor thread, and a provider's stop hook
+        // can wait on the very call that delivered it (the HTTP server waits for its
+        // in-flight requests), so dropped providers are stopped on a thread of their own
+        let mut retired = Vec::new();
+        for capid in dropped.iter() {
+            info!("Live update dropped capability {}", capid);
+            match self.detach_capability(capid) {
+                Ok(provider) => retired.push(provider),
+                Err(e) => warn!("Capability {} not unloaded: {}", capid, e),
+            }
+        }
+        retire_in_background(retired);
+        let added: Vec<String> = new_caps
+            .into_iter()
+            .filter(|capid| !self.plugins.contains_key(capid))
+            .collect();
+        let mut loaded = Vec::new();
+        for capid in added.iter() {
+            info!("Live update added capability {}", capid);
+            match self.load_from_source(capid) {
+                Ok(capid) => loaded.push(capid),
+                Err(e) => error!("Capability provider for {} not loaded: {}", capid, e),
+            }
+        }
+
+        let identity = self.module_id_for_claims();
+        if identity.issuer == old_identity.issuer
+            && identity.module_name == old_identity.module_name
+            && identity.capabilities == old_identity.capabilities
+        {
+            return;
+        }
+        for (capid, plugin) in self.plugins.iter() {
+            if loaded.contains(capid) {
+                continue;
+            }
+            let dispatcher = match self.dispatchers.get(capid) {
+                Some(dispatcher) => dispatcher.clone(),
+                None => continue,
+            };
+            if let Err(e) = plugin.configure_dispatch(Box::new(dispatcher), identity.clone()) {
+                error!("Failed to reconfigure provider for {}: {}", capid, e);
+            }
+        }
+    }
+
+    fn load_from_source(&mut self, capid: &str) -> Result<String> {
+        match self.sources.get(capid).cloned() {
+            Some(ProviderSource::Library(path)) => unsafe { self.load_plugin(path) },
+            Some(ProviderSource::Remote(program)) => self.load_remote_plugin(program),
+            None => Err(errors::new(errors::ErrorKind::NoSuchCapability(
+                capid.to_string(),
+            ))),
+        }
+    }
+
+    fn is_claimed(&self, capid: &str) -> bool {
+        self.check_claimed(capid).is_ok()
+    }
+
+    fn module_subject(&self) -> &str {
+        self.claims
+            .as_ref()
+            .map_or("(unknown module)", |c| &c.subject)
+    }
+
     fn module_id_for_claims(&self) -> codec::capabilities::ModuleIdentity {
         match self.claims {
             Some(ref c) => codec::capabilities::ModuleIdentity {
@@ -83,28 +408,134 @@ impl CapabilityManager {
         }
     }
 
-    pub unsafe fn load_plugin<P: AsRef<OsStr>>(&mut self, filename: P) -> Result<String> {
-        type PluginCreate = unsafe fn() -> *mut CapabilityProvider;
+    /// Sets the settings handed to this manager's providers, so that providers (such as
+    /// the HTTP server with its port, or NATS with its subscription) can be configured
+    /// differently for each module. Library providers receive them when they're created,
+    /// if they export `__capability_provider_create_with_settings`, and provider
+    /// processes as environment variables. Applies to providers loaded after it's set
+    pub fn set_provider_settings(&mut self, settings: Vec<(String, String)>) {
+        self.provider_settings = settings
+    }
+
+    /// Sets how long host calls to a provider process, and its dispatches to the guest
+    /// module, wait for a reply. Applies to provider processes launched after it's set
+    pub fn set_remote_call_timeout(&mut self, timeout: Duration) {
+        self.remote_call_timeout = timeout
+    }
 
-        let lib = Library::new(filename.as_ref())?;
+    pub unsafe fn load_plugin<P: AsRef<Path>>(&mut self, filename: P) -> Result<String> {
+        let filename = filename.as_ref();
+        // Each manager gets its own copy of the library, so providers loaded for several
+        // guest modules in one process don't share the library's global state
+        let (lib, plugin) = load_library_copy(filename, &self.provider_settings)?;
+        self.sources.insert(
+            plugin.capability_id().to_string(),
+            ProviderSource::Library(filename.to_path_buf()),
+        );
 
         // We need to keep the library around otherwise our plugin's vtable will
-        // point to garbage. We do this little dance to make sure the library
-        // doesn't end up getting moved.
-        self.loaded_libraries.push(lib);
+        // point to garbage. If registration fails, the plugin has already been
+        // dropped by the time the library is
+        let capid = self.register_provider(plugin)?;
+        self.offer_async_dispatch(&capid, &lib);
+        self.loaded_libraries.insert(capid.clone(), lib);
+
+        Ok(capid)
+    }
+
+    /// Replaces the provider for a capability with the one in the given library. The new
+    /// provider takes over the old provider's multiplexer channels, so the guest module
+    /// never sees the capability go away. A library for a capability that isn't loaded
+    /// yet is simply loaded
+    pub unsafe fn reload_plugin<P: AsRef<Path>>(&mut self, filename: P) -> Result<String> {
+        let filename = filename.as_ref();
+        let (lib, plugin) = load_library_copy(filename, &self.provider_settings)?;
+
+        let capid = plugin.capability_id().to_string();
+        self.sources.insert(
+            capid.clone(),
+            ProviderSource::Library(filename.to_path_buf()),
+        );
+        let dispatcher = match self.dispatchers.get(&capid) {
+            Some(dispatcher) => dispatcher.clone(),
+            None => {
+                let capid = self.register_provider(plugin)?;
+                self.offer_async_dispatch(&capid, &lib);
+                self.loaded_libraries.insert(capid.clone(), lib);
+                return Ok(capid);
+            }
+        };
+        if let Err(e) = self.check_claimed(&capid) {
+            drop(plugin);
+            drop(lib);
+            return Err(e);
+        }
+
+        // The new provider is configured while the old one is still running, so that if
+        // it fails the old one carries on serving the capability
+        let configured =
+            plugin.configure_dispatch(Box::new(dispatcher), self.module_id_for_claims());
+        if let Err(e) = configured {
+            retire_in_background(vec![(plugin, Some(lib))]);
+            return Err(errors::new(errors::ErrorKind::ProviderConfiguration(e)));
+        }
+        info!("Reloaded capability {}, provider: {}", capid, plugin.name());
+        let old = self.plugins.insert(capid.clone(), plugin);
+        let oldlib = self.loaded_libraries.insert(capid.clone(), lib);
+        self.offer_async_dispatch(&capid, &self.loaded_libraries[&capid]);
+
+        // The caller holds this manager's lock, which guest calls need for their host
+        // calls, and the old provider's stop hook can wait on guest calls (the HTTP server
+        // waits for its in-flight requests), so it's stopped on a thread of its own. Its
+        // library is only unloaded once the hook confirms none of its threads are running
+        match old {
+            Some(old) => retire_in_background(vec![(old, oldlib)]),
+            None => drop(oldlib),
+        }
+
+        Ok(capid)
+    }
 
-        let lib = self.loaded_libraries.last().unwrap();
+    /// Hands the provider a dispatch returning futures, if its library exports the hook
+    /// for one, so it can wait for the guest's replies without tying up a thread each
+    unsafe fn offer_async_dispatch(&self, capid: &str, lib: &Library) {
+        type PluginAsyncDispatch = unsafe fn(&dyn CapabilityProvider, AsyncDispatch);
 
-        let constructor: Symbol<PluginCreate> = lib.get(b"__capability_provider_create")?;
-        let boxed_raw = constructor();
+        let hook = match lib.get::<PluginAsyncDispatch>(b"__capability_provider_async_dispatch") {
+            Ok(hook) => hook,
+            Err(_) => return,
+        };
+        if let (Some(plugin), Some(dispatcher)) =
+            (self.plugins.get(capid), self.dispatchers.get(capid))
+        {
+            hook(plugin.as_ref(), dispatcher.async_dispatch());
+        }
+    }
 
-        let plugin = Box::from_raw(boxed_raw);
+    /// Launches a capability provider executable as a child process, communicating
+    /// with it over a Unix domain socket
+    pub fn load_remote_plugin<P: AsRef<Path>>(&mut self, program: P) -> Result<String> {
+        let plugin = RemoteProvider::launch(
+            program.as_ref(),
+            self.provider_settings.clone(),
+            self.remote_call_timeout,
+        )?;
+        self.sources.insert(
+            plugin.capability_id().to_string(),
+            ProviderSource::Remote(program.as_ref().to_path_buf()),
+        );
         info!(
-            "Loaded capability: {}, provider: {}",
+            "Launched capability provider process: {}, provider: {}",
             plugin.capability_id(),
             plugin.name()
         );
 
+        self.register_provider(Box::new(plugin))
+    }
+
+    /// Checks the provider against the module's claims, binds it to the multiplexer and
+    /// hands it a dispatcher
+    fn register_provider(&mut self, plugin: Box<dyn CapabilityProvider>) -> Result<String> {
         let capid = plugin.capability_id().to_string();
 
         if self.plugins.contains_key(&capid) {
@@ -114,56 +545,279 @@ impl CapabilityManager {
             );
         }
 
-        if let Some(ref claims) = self.claims {
-            if let Some(ref caps) = claims.caps {
-                if !caps.contains(&capid) {
-                    let lib = self.loaded_libraries.pop();
-                    drop(lib); // Could let this go out of scope, but I wanted to be explicit here
-                    info!(
-                        "Capability provider for {} not claimed by guest module. Unloading.",
-                        capid
-                    );
-                    return Err(errors::new(errors::ErrorKind::WascapViolation(format!(
-                        "Unauthorized capability: {}",
-                        capid
-                    ))));
-                }
-            }
+        if let Err(e) = self.check_claimed(&capid) {
+            info!(
+                "Capability provider for {} not claimed by guest module. Unloading.",
+                capid
+            );
+            return Err(e);
         }
 
-        let (evt_s, evt_r) = channel::unbounded();
-        let (cmd_s, cmd_r) = channel::unbounded();
-        let spatch = WaxosuitDispatcher::new(evt_r, cmd_s);
-
+        let queue = self.queues.get(&capid).unwrap_or(&self.default_queue);
+        let (mut spatch, cmd_r) = WaxosuitDispatcher::new(queue, self.dispatch_timeout);
+        if let Some(max_pending) = self.one_way.get(&capid) {
+            info!("Dispatches from {} are one-way", capid);
+            spatch = spatch.one_way(*max_pending, self.dead_letter.clone());
+        }
+        self.dispatchers.insert(capid.clone(), spatch.clone());
 
         self.muxer
-            .register_capability(plugin.capability_id(), cmd_r, evt_s)?;
+            .register_capability(plugin.capability_id(), cmd_r)?;
 
-        plugin
-            .configure_dispatch(Box::new(spatch), self.module_id_for_claims())
-            .unwrap();
+        if let Err(e) = plugin.configure_dispatch(Box::new(spatch), self.module_id_for_claims()) {
+            self.dispatchers.remove(&capid);
+            self.muxer.deregister_capability(&capid)?;
+            return Err(errors::new(errors::ErrorKind::ProviderConfiguration(e)));
+        }
         //plugin.on_plugin_load();
         self.plugins
             .insert(plugin.capability_id().to_string(), plugin);
 
         Ok(capid)
     }
-    /// Unload all plugins and loaded plugin libraries, making sure to fire
-    /// their `on_plugin_unload()` methods so they can do any necessary cleanup.
+
+    /// Fails unless the module may use the capability. A module that claims no list of
+    /// capabilities, or that has no claims at all, may use any
+    fn check_claimed(&self, capid: &str) -> Result<()> {
+        let caps = self.claims.as_ref().and_then(|c| c.caps.as_ref());
+        if claims_capability(caps, capid) {
+            Ok(())
+        } else {
+            Err(errors::new(errors::ErrorKind::WascapViolation(format!(
+                "Unauthorized capability: {}",
+                capid
+            ))))
+        }
+    }
+
+    /// Stops the provider for a capability and removes its channel from the multiplexer.
+    /// The provider's stop hook runs before the library containing its code is unloaded,
+    /// and the library stays loaded unless the hook confirms the provider's threads are gone
+    pub fn unload_capability(&mut self, capid: &str) -> Result<()> {
+        let (plugin, lib) = self.detach_capability(capid)?;
+        info!("Stopping capability provider: {}", plugin.name());
+        retire_provider(plugin, lib);
+        Ok(())
+    }
+
+    /// Removes the provider for a capability, and its channel from the multiplexer,
+    /// handing back the still running provider along with the library it came from
+    fn detach_capability(
+        &mut self,
+        capid: &str,
+    ) -> Result<(Box<dyn CapabilityProvider>, Option<Library>)> {
+        let plugin = match self.plugins.remove(capid) {
+            Some(plugin) => plugin,
+            None => {
+                return Err(errors::new(errors::ErrorKind::NoSuchCapability(
+                    capid.to_string(),
+                )))
+            }
+        };
+        self.muxer.deregister_capability(capid)?;
+        self.dispatchers.remove(capid);
+
+        Ok((plugin, self.loaded_libraries.remove(capid)))
+    }
+
+    /// Unloads the providers that aren't waiting on the guest module to reply to a
+    /// dispatch, returning the capabilities whose providers were left loaded. Stopping a
+    /// provider can mean waiting for its dispatches, and a guest call can't finish while
+    /// its host calls wait on this manager, so busy providers are left to the process exit
+    pub fn unload_idle(&mut self) -> Vec<String> {
+        let busy: Vec<String> = self
+            .dispatchers
+            .iter()
+            .filter(|(_, dispatcher)| dispatcher.in_flight() > 0)
+            .map(|(capid, _)| capid.clone())
+            .collect();
+        let idle: Vec<String> = self
+            .plugins
+            .keys()
+            .filter(|capid| !busy.contains(capid))
+            .cloned()
+            .collect();
+        for capid in idle {
+            if let Err(e) = self.unload_capability(&capid) {
+                error!("Failed to unload capability {}: {}", capid, e);
+            }
+        }
+        busy
+    }
+
+    /// Unload all plugins and loaded plugin libraries, stopping each plugin
+    /// before the library containing it is unloaded.
     pub fn unload(&mut self) {
         info!("Unloading plugins");
 
-        //for (_k, plugin) in self.plugins.drain() {
-        //println!("Firing on_plugin_unload for {:?}", plugin.name());
-        //plugin.on_plugin_unload();
-        //}
+        let capids: Vec<String> = self.plugins.keys().cloned().collect();
+        for capid in capids {
+            if let Err(e) = self.unload_capability(&capid) {
+                error!("Failed to unload capability {}: {}", capid, e);
+            }
+        }
 
-        for lib in self.loaded_libraries.drain(..) {
+        for (_, lib) in self.loaded_libraries.drain() {
             drop(lib);
         }
     }
 }
 
+fn claims_capability(caps: Option<&Vec<String>>, capid: &str) -> bool {
+    caps.map_or(true, |caps| caps.iter().any(|c| c == capid))
+}
+
+fn update_rejected(reason: impl Into<String>) -> errors::Error {
+    let reason = reason.into();
+    warn!(target: AUDIT_TARGET, "REJECTED live update: {}", reason);
+    errors::new(errors::ErrorKind::LiveUpdateRejected(reason))
+}
+
+/// Calls a provider's stop hook, drops it, and then unloads its library, but only if the
+/// hook reported that every thread the provider started has exited. Unloading a library
+/// whose code is still running crashes the process, so a library that can't confirm this,
+/// or that doesn't export the hook, is deliberately leaked instead
+fn retire_provider(plugin: Box<dyn CapabilityProvider>, lib: Option<Library>) {
+    type PluginStop = unsafe fn(&dyn CapabilityProvider) -> bool;
+
+    let lib = match lib {
+        Some(lib) => lib,
+        // Remote providers stop their process when dropped
+        None => {
+            drop(plugin);
+            return;
+        }
+    };
+    let stopped = unsafe {
+        match lib.get::<PluginStop>(b"__capability_provider_stop") {
+            Ok(stop) => stop(plugin.as_ref()),
+            Err(_) => false,
+        }
+    };
+    let capid = plugin.capability_id().to_string();
+    drop(plugin);
+
+    if stopped {
+        drop(lib);
+    } else {
+        warn!(
+            "Provider for {} may still have threads running, leaving its library loaded",
+            capid
+        );
+        std::mem::forget(lib);
+    }
+}
+
+/// Retires the providers on a thread of their own, for callers whose locks the providers'
+/// stop hooks could end up waiting on
+fn retire_in_background(retired: Vec<(Box<dyn CapabilityProvider>, Option<Library>)>) {
+    if retired.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        for (plugin, lib) in retired {
+            info!("Stopping capability provider: {}", plugin.name());
+            retire_provider(plugin, lib);
+        }
+    });
+}
+
+/// Creates the provider in the library, handing it the settings if the library exports a
+/// constructor that takes them
+unsafe fn load_library<P: AsRef<OsStr>>(
+    filename: P,
+    settings: &[(String, String)],
+) -> Result<(Library, Box<dyn CapabilityProvider>)> {
+    type PluginCreate = unsafe fn() -> *mut dyn CapabilityProvider;
+    type PluginCreateWithSettings =
+        unsafe fn(&HashMap<String, String>) -> *mut dyn CapabilityProvider;
+
+    let lib = Library::new(filename.as_ref())?;
+
+    let plugin =
+        match lib.get::<PluginCreateWithSettings>(b"__capability_provider_create_with_settings") {
+            Ok(constructor) => {
+                let settings: HashMap<String, String> = settings.iter().cloned().collect();
+                Box::from_raw(constructor(&settings))
+            }
+            Err(_) => {
+                if !settings.is_empty() {
+                    warn!(
+                        "Capability provider in {} takes no settings, ignoring them",
+                        filename.as_ref().to_string_lossy()
+                    );
+                }
+                let constructor: Symbol<PluginCreate> = lib.get(b"__capability_provider_create")?;
+                Box::from_raw(constructor())
+            }
+        };
+    info!(
+        "Loaded capability: {}, provider: {}",
+        plugin.capability_id(),
+        plugin.name()
+    );
+
+    Ok((lib, plugin))
+}
+
+/// Loads a library from a private copy of the file. The dynamic loader hands back the
+/// library it already has for a file it has seen before, which would leave a reloaded
+/// provider running old code, and providers for different modules sharing globals
+unsafe fn load_library_copy(
+    filename: &Path,
+    settings: &[(String, String)],
+) -> Result<(Library, Box<dyn CapabilityProvider>)> {
+    let copy = copy_library(filename)?;
+    let loaded = load_library(&copy, settings);
+    let _ = fs::remove_file(&copy);
+    loaded
+}
+
+/// Copies the library into the private library directory. The copy is created there
+/// rather than opened, so nobody else can have put a file or link in its place
+fn copy_library(filename: &Path) -> Result<PathBuf> {
+    let n = LIBRARY_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let name = filename
+        .file_name()
+        .map_or("provider".into(), |f| f.to_string_lossy());
+    let copy = library_dir()?.join(format!("{}-{}", n, name));
+
+    let mut source = fs::File::open(filename)?;
+    let mut dest = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&copy)?;
+    io::copy(&mut source, &mut dest)?;
+    Ok(copy)
+}
+
+/// The directory for copies of provider libraries, which only this user can access. It's
+/// created under a random name, and creating it fails if anything already has that name
+fn library_dir() -> Result<PathBuf> {
+    let mut dir = LIBRARY_DIR.lock().unwrap();
+    if let Some(ref dir) = *dir {
+        return Ok(dir.clone());
+    }
+    loop {
+        let mut suffix = [0u8; 8];
+        fs::File::open("/dev/urandom")?.read_exact(&mut suffix)?;
+        let path = std::env::temp_dir().join(format!(
+            "waxosuit-{}-{:016x}",
+            std::process::id(),
+            u64::from_le_bytes(suffix)
+        ));
+        match fs::DirBuilder::new().mode(0o700).create(&path) {
+            Ok(()) => {
+                *dir = Some(path.clone());
+                return Ok(path);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 impl Drop for CapabilityManager {
     fn drop(&mut self) {
         if !self.plugins.is_empty() || !self.loaded_libraries.is_empty() {
@@ -171,3 +825,75 @@ impl Drop for CapabilityManager {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error;
+
+    struct EchoProvider;
+
+    impl CapabilityProvider for EchoProvider {
+        fn capability_id(&self) -> &'static str {
+            "wascap:echo"
+        }
+
+        fn configure_dispatch(
+            &self,
+            _dispatcher: Box<dyn Dispatcher>,
+            _id: ModuleIdentity,
+        ) -> ::std::result::Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn name(&self) -> &'static str {
+            "Echo Provider"
+        }
+
+        fn handle_call(&self, _cmd: &Command) -> ::std::result::Result<Event, Box<dyn Error>> {
+            Ok(Event {
+                success: true,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn host_call(capid: &str) -> Command {
+        Command {
+            source: "guest".to_string(),
+            target_cap: capid.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn missing_capability_list_claims_everything() {
+        assert!(claims_capability(None, "wascap:keyvalue"));
+    }
+
+    #[test]
+    fn capability_list_claims_only_its_capabilities() {
+        let caps = vec!["wascap:keyvalue".to_string()];
+        assert!(claims_capability(Some(&caps), "wascap:keyvalue"));
+        assert!(!claims_capability(Some(&caps), "wascap:messaging"));
+        assert!(!claims_capability(Some(&vec![]), "wascap:keyvalue"));
+    }
+
+    #[test]
+    fn module_without_claims_may_call_loaded_providers() {
+        let mut capman = CapabilityManager::new();
+        capman.register_provider(Box::new(EchoProvider)).unwrap();
+
+        let evt = capman.call(&host_call("wascap:echo")).unwrap();
+        assert!(evt.success);
+    }
+
+    #[test]
+    fn calls_to_unloaded_capabilities_are_not_found() {
+        let capman = CapabilityManager::new();
+
+        let evt = capman.call(&host_call("wascap:keyvalue")).unwrap();
+        assert!(!evt.success);
+        assert_eq!(evt.error.unwrap().code, 404);
+    }
+}
@@ -12,24 +12,482 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crossbeam_channel::{Receiver, Select, Sender};
+use crate::mux::failure_event;
+use crate::wasm::is_live_update;
+use crossbeam_channel as channel;
+use crossbeam_channel::{
+    Receiver, RecvTimeoutError, Select, SendTimeoutError, Sender, TrySendError,
+};
+use futures::sync::oneshot;
+use futures::{future, Async, Future, Poll};
+use futures_timer::Delay;
 use std::error::Error;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use wascap_codec::capabilities::Dispatcher;
 use wascap_codec::core::{Command, Event};
 
+/// A command dispatched by a capability provider, paired with where that one dispatch
+/// awaits its reply. Giving every dispatch its own reply channel means concurrent
+/// dispatches from the same provider can never receive each other's events
+pub(crate) type DispatchRequest = (Command, ReplyTo);
+
+/// What a dispatcher does with a command when its capability's queue is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Wait for room in the queue, up to the dispatch timeout. Asynchronous dispatches
+    /// can't wait, so they fail with `ErrorKind::WouldBlock` instead
+    Block,
+    /// Discard the new command, answering it with a failure event
+    DropNewest,
+    /// Discard the oldest queued command, answering it with a failure event
+    DropOldest,
+    /// Fail the dispatch with `ErrorKind::WouldBlock`
+    Reject,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "reject" => Ok(OverflowPolicy::Reject),
+            _ => Err(format!(
+                "Unknown overflow policy '{}', expected block, drop-newest, drop-oldest or reject",
+                s
+            )),
+        }
+    }
+}
+
+/// The size of a capability's queue of commands bound for the guest module, and what
+/// happens when it fills up
+#[derive(Clone, Debug)]
+pub struct QueueOptions {
+    /// The number of commands that may wait in the queue, or `None` for no limit
+    pub capacity: Option<usize>,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            capacity: None,
+            policy: OverflowPolicy::Block,
+        }
+    }
+}
+
+/// A snapshot of a capability's queue
+#[derive(Clone, Debug)]
+pub struct QueueStatus {
+    /// The number of commands waiting for the multiplexer
+    pub depth: usize,
+    pub capacity: Option<usize>,
+    /// The number of commands dropped or rejected because the queue was full
+    pub overflowed: usize,
+}
+
+/// Where the multiplexer delivers the guest module's reply to a dispatched command
+pub(crate) enum ReplyTo {
+    /// A dispatcher blocked waiting for the reply
+    Channel(Sender<Event>),
+    /// A `DispatchHandle` future
+    Future(oneshot::Sender<Event>),
+    /// Nobody is waiting for the reply to a one-way dispatch, so a failure is handed
+    /// to the dead-letter hook along with the command that caused it
+    OneWay(Command, OneWay),
+}
+
+impl ReplyTo {
+    /// Tells whoever is waiting that the reply will never arrive. A blocked dispatch or
+    /// a `DispatchHandle` fails with `ErrorKind::NotConnected`
+    pub(crate) fn disconnect(self) {
+        match self {
+            ReplyTo::OneWay(..) => {
+                self.send(failure_event(503, "Multiplexer is no longer running"));
+            }
+            // Dropping the sender wakes the receiver with an error
+            _ => {}
+        }
+    }
+
+    /// Delivers the reply, returning `false` if nobody is waiting for it any more
+    pub(crate) fn send(self, evt: Event) -> bool {
+        match self {
+            ReplyTo::Channel(s) => s.send(evt).is_ok(),
+            ReplyTo::Future(s) => s.send(evt).is_ok(),
+            ReplyTo::OneWay(cmd, one_way) => {
+                one_way.pending.fetch_sub(1, Ordering::SeqCst);
+                if !evt.success {
+                    (one_way.dead_letter)(&cmd, &evt);
+                }
+                true
+            }
+        }
+    }
+}
+
+/// The receiving end of a capability's queue, held by the multiplexer. The queue stays
+/// connected for as long as its dispatcher exists, since the dispatcher needs to take
+/// from it to drop the oldest command, so the multiplexer closes it instead when it
+/// stops listening. Closing fails every request still waiting, and every later dispatch,
+/// with `ErrorKind::NotConnected`
+#[derive(Clone)]
+pub(crate) struct RequestQueue {
+    pub(crate) requests: Receiver<DispatchRequest>,
+    closed: Arc<AtomicBool>,
+}
+
+impl RequestQueue {
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        disconnect_waiting(&self.requests);
+    }
+}
+
+fn disconnect_waiting(requests: &Receiver<DispatchRequest>) {
+    for (_, reply_to) in requests.try_iter() {
+        reply_to.disconnect();
+    }
+}
+
+/// Called with each one-way command the guest module failed to handle, and the
+/// failure event it produced
+pub type DeadLetterHook = Arc<dyn Fn(&Command, &Event) + Send + Sync>;
+
+/// The default dead-letter hook, which logs the failure
+pub fn log_dead_letter(cmd: &Command, evt: &Event) {
+    error!(
+        target: "waxosuit::deadletter",
+        "One-way dispatch from {} failed: {}",
+        cmd.source,
+        evt.error
+            .as_ref()
+            .map_or("(no error)".to_string(), |e| e.description.clone())
+    );
+}
+
+/// Settings for a dispatcher whose commands are queued for the guest without waiting
+/// for the reply
+#[derive(Clone)]
+pub(crate) struct OneWay {
+    pending: Arc<AtomicUsize>,
+    max_pending: Option<usize>,
+    dead_letter: DeadLetterHook,
+}
+
+/// A dispatch that returns a future rather than blocking until the guest replies. The
+/// `Dispatcher` trait can only block, so this is handed to providers whose library
+/// exports `__capability_provider_async_dispatch`, which must define the same type
+pub type AsyncDispatch =
+    Arc<dyn Fn(&Command) -> Box<dyn Future<Item = Event, Error = io::Error> + Send> + Send + Sync>;
+
+/// A future resolved with the guest module's reply to a command sent with
+/// `WaxosuitDispatcher::dispatch_async`, or failed with `ErrorKind::TimedOut` if the
+/// reply doesn't arrive within the dispatch timeout
+pub struct DispatchHandle {
+    reply: oneshot::Receiver<Event>,
+    error: Option<io::Error>,
+    deadline: Option<(Delay, Duration)>,
+    _in_flight: InFlight,
+}
+
+/// Counts a dispatch as in flight for as long as it's alive
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(count: &Arc<AtomicUsize>) -> InFlight {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Future for DispatchHandle {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Event, io::Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if let Async::Ready(evt) = self.reply.poll().map_err(|_| disconnected())? {
+            return Ok(Async::Ready(evt));
+        }
+        match self.deadline {
+            Some((ref mut delay, t)) => match delay.poll()? {
+                Async::Ready(()) => Err(no_reply(t)),
+                Async::NotReady => Ok(Async::NotReady),
+            },
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
 /// A dispatcher is given to each capability provider, allowing it to send
 /// commands in to the guest module (via the muxer) and await replies. This dispatch
-/// is one way, and is _not_ used for the guest module to send commands to capabilities
+/// is one way, and is _not_ used for the guest module to send commands to capabilities.
+/// Each dispatch carries its own reply channel, so a provider may dispatch from as
+/// many threads at once as it likes.
+///
+/// Failures are reported as `std::io::Error`s so providers can inspect them without
+/// depending on the host: a reply that doesn't arrive within the dispatch timeout is
+/// `ErrorKind::TimedOut`, and a multiplexer that's gone away is `ErrorKind::NotConnected`.
+/// What happens when the capability's queue is full depends on its `OverflowPolicy`.
+///
+/// A one-way dispatcher returns a successful event with no payload as soon as the command
+/// is queued, rather than the guest's reply. If it already has its maximum number of
+/// commands pending the dispatch fails with `ErrorKind::WouldBlock`
 #[derive(Clone)]
-pub(crate) struct WaxosuitDispatcher {
-    evt_r: Receiver<Event>,
-    cmd_s: Sender<Command>,
+pub struct WaxosuitDispatcher {
+    cmd_s: Sender<DispatchRequest>,
+    cmd_r: Receiver<DispatchRequest>,
+    closed: Arc<AtomicBool>,
+    policy: OverflowPolicy,
+    overflowed: Arc<AtomicUsize>,
+    in_flight: Arc<AtomicUsize>,
+    timeout: Option<Duration>,
+    one_way: Option<OneWay>,
 }
 
 impl WaxosuitDispatcher {
-    pub fn new(evt_r: Receiver<Event>, cmd_s: Sender<Command>) -> WaxosuitDispatcher {
-        WaxosuitDispatcher { evt_r, cmd_s }
+    /// Creates a dispatcher along with its queue, the receiving end of which should be
+    /// registered with the multiplexer
+    pub(crate) fn new(
+        queue: &QueueOptions,
+        timeout: Option<Duration>,
+    ) -> (WaxosuitDispatcher, RequestQueue) {
+        let (cmd_s, cmd_r) = match queue.capacity {
+            Some(capacity) => channel::bounded(capacity),
+            None => channel::unbounded(),
+        };
+        let closed = Arc::new(AtomicBool::new(false));
+        let dispatcher = WaxosuitDispatcher {
+            cmd_s,
+            cmd_r: cmd_r.clone(),
+            closed: closed.clone(),
+            policy: queue.policy,
+            overflowed: Arc::new(AtomicUsize::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            timeout,
+            one_way: None,
+        };
+
+        (
+            dispatcher,
+            RequestQueue {
+                requests: cmd_r,
+                closed,
+            },
+        )
+    }
+
+    /// Reports how full this dispatcher's queue is
+    pub fn queue_status(&self) -> QueueStatus {
+        QueueStatus {
+            depth: self.cmd_s.len(),
+            capacity: self.cmd_s.capacity(),
+            overflowed: self.overflowed.load(Ordering::SeqCst),
+        }
+    }
+
+    /// The number of dispatches waiting on a reply from the guest module. One-way
+    /// dispatches aren't counted, as nothing waits on them
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Switches the dispatcher to one-way mode, allowing at most `max_pending` commands
+    /// to be queued for the guest at a time
+    pub(crate) fn one_way(
+        mut self,
+        max_pending: Option<usize>,
+        dead_letter: DeadLetterHook,
+    ) -> WaxosuitDispatcher {
+        self.one_way = Some(OneWay {
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending,
+            dead_letter,
+        });
+        self
+    }
+
+    /// Queues the command for the guest without waiting for its reply. Only waits for
+    /// room in the queue if `may_block` is set
+    fn enqueue(&self, cmd: &Command, one_way: &OneWay, may_block: bool) -> io::Result<Event> {
+        let pending = one_way.pending.fetch_add(1, Ordering::SeqCst);
+        if one_way.max_pending.map_or(false, |max| pending >= max) {
+            one_way.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("One-way queue for {} is full", cmd.source),
+            ));
+        }
+        let request = (cmd.clone(), ReplyTo::OneWay(cmd.clone(), one_way.clone()));
+        if let Err(e) = self.submit(request, may_block) {
+            // The command never made it into the queue, so its reply won't be delivered
+            one_way.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        Ok(Event {
+            success: true,
+            ..Default::default()
+        })
     }
+
+    /// Sends the command to the muxer without blocking, returning a future that
+    /// resolves once the guest module has replied. The dispatch timeout applies as it
+    /// does to `dispatch`, and a full queue never holds up the caller, whatever the
+    /// overflow policy
+    pub fn dispatch_async(&self, cmd: &Command) -> DispatchHandle {
+        let in_flight = InFlight::new(&self.in_flight);
+        let (evt_s, evt_r) = oneshot::channel();
+        let error = self
+            .submit((cmd.clone(), ReplyTo::Future(evt_s)), false)
+            .err();
+        // Live updates are limited by the multiplexer's live update timeout instead
+        let deadline = match self.timeout {
+            Some(t) if !is_live_update(cmd) => Some((Delay::new(t), t)),
+            _ => None,
+        };
+
+        DispatchHandle {
+            reply: evt_r,
+            error,
+            deadline,
+            _in_flight: in_flight,
+        }
+    }
+
+    /// Wraps this dispatcher's `dispatch_async` for handing to a provider. A one-way
+    /// dispatcher's future resolves as soon as the command is queued, as its `dispatch` does
+    pub fn async_dispatch(&self) -> AsyncDispatch {
+        let dispatcher = self.clone();
+        Arc::new(
+            move |cmd: &Command| -> Box<dyn Future<Item = Event, Error = io::Error> + Send> {
+                match dispatcher.one_way {
+                    Some(ref one_way) => {
+                        Box::new(future::result(dispatcher.enqueue(cmd, one_way, false)))
+                    }
+                    None => Box::new(dispatcher.dispatch_async(cmd)),
+                }
+            },
+        )
+    }
+
+    /// Puts the request on the capability's queue, unless the multiplexer has closed it.
+    /// Unless `may_block` is set, a full queue is never waited on
+    fn submit(&self, request: DispatchRequest, may_block: bool) -> io::Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(disconnected());
+        }
+        self.push(request, may_block)?;
+        // The queue may have been closed after the check above but before the multiplexer
+        // could see this request, in which case nobody else will fail it
+        if self.closed.load(Ordering::SeqCst) {
+            disconnect_waiting(&self.cmd_r);
+        }
+        Ok(())
+    }
+
+    /// Adds the request to the queue, applying the overflow policy if it's full
+    fn push(&self, request: DispatchRequest, may_block: bool) -> io::Result<()> {
+        let request = match self.policy {
+            OverflowPolicy::Block if may_block => return self.send_blocking(request),
+            _ => match self.cmd_s.try_send(request) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(_)) => return Err(disconnected()),
+                Err(TrySendError::Full(request)) => request,
+            },
+        };
+        let source = request.0.source.clone();
+        self.overflowed.fetch_add(1, Ordering::SeqCst);
+
+        match self.policy {
+            OverflowPolicy::DropOldest => {
+                if let Ok((oldest, reply_to)) = self.cmd_r.try_recv() {
+                    warn!("Queue for {} is full, dropping oldest command", source);
+                    reply_to.send(dropped(&oldest.source));
+                }
+                if let Err(e) = self.cmd_s.try_send(request) {
+                    // Another dispatch took the space that was freed
+                    let (newest, reply_to) = e.into_inner();
+                    reply_to.send(dropped(&newest.source));
+                }
+                Ok(())
+            }
+            OverflowPolicy::DropNewest => {
+                warn!("Queue for {} is full, dropping newest command", source);
+                request.1.send(dropped(&source));
+                Ok(())
+            }
+            _ => {
+                warn!("Queue for {} is full, rejecting command", source);
+                Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!("Queue for {} is full", source),
+                ))
+            }
+        }
+    }
+
+    /// Waits for room in the queue, for no longer than the dispatch timeout
+    fn send_blocking(&self, request: DispatchRequest) -> io::Result<()> {
+        match self.timeout {
+            Some(t) => self.cmd_s.send_timeout(request, t).map_err(|e| match e {
+                SendTimeoutError::Timeout(_) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Queue still full after {}ms", t.as_millis()),
+                ),
+                SendTimeoutError::Disconnected(_) => disconnected(),
+            }),
+            None => self.cmd_s.send(request).map_err(|_| disconnected()),
+        }
+    }
+
+    fn await_reply(&self, evt_r: &Receiver<Event>) -> io::Result<Event> {
+        match self.timeout {
+            Some(t) => evt_r.recv_timeout(t).map_err(|e| match e {
+                RecvTimeoutError::Timeout => no_reply(t),
+                RecvTimeoutError::Disconnected => disconnected(),
+            }),
+            None => evt_r.recv().map_err(|_| disconnected()),
+        }
+    }
+}
+
+fn dropped(capability: &str) -> Event {
+    failure_event(
+        503,
+        format!("Command dropped, queue for {} is full", capability),
+    )
+}
+
+fn no_reply(t: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("No reply from guest module within {}ms", t.as_millis()),
+    )
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "Multiplexer is no longer running",
+    )
 }
 
 impl Dispatcher for WaxosuitDispatcher {
@@ -43,9 +501,190 @@ impl Dispatcher for WaxosuitDispatcher {
                 .map_or("(no payload)".to_string(), |p| format!("{}", p.type_url)),
             cmd.source
         );
-        self.cmd_s.send(cmd.clone()).unwrap();
-        let evt = self.evt_r.recv().unwrap();
+        if let Some(ref one_way) = self.one_way {
+            return Ok(self.enqueue(cmd, one_way, true)?);
+        }
+        let _in_flight = InFlight::new(&self.in_flight);
+        let (evt_s, evt_r) = channel::bounded(1);
+        self.submit((cmd.clone(), ReplyTo::Channel(evt_s)), true)?;
+        // A live update takes as long as its compilation and health checks, which the
+        // multiplexer limits with its live update timeout instead
+        let evt = if is_live_update(cmd) {
+            evt_r.recv().map_err(|_| disconnected())?
+        } else {
+            self.await_reply(&evt_r)?
+        };
 
         Ok(evt)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn dispatcher(capacity: usize, policy: OverflowPolicy) -> (WaxosuitDispatcher, RequestQueue) {
+        let queue = QueueOptions {
+            capacity: Some(capacity),
+            policy,
+        };
+        WaxosuitDispatcher::new(&queue, Some(Duration::from_millis(50)))
+    }
+
+    fn command(n: usize) -> Command {
+        Command {
+            source: format!("capability-{}", n),
+            target_cap: "guest".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Submits a command, returning the receiver its reply will be delivered to
+    fn submit(dispatcher: &WaxosuitDispatcher, n: usize) -> (io::Result<()>, Receiver<Event>) {
+        let (evt_s, evt_r) = channel::bounded(1);
+        let res = dispatcher.submit((command(n), ReplyTo::Channel(evt_s)), true);
+        (res, evt_r)
+    }
+
+    fn queued(queue: &RequestQueue) -> Vec<String> {
+        queue
+            .requests
+            .try_iter()
+            .map(|(cmd, _)| cmd.source)
+            .collect()
+    }
+
+    fn assert_dropped(evt_r: &Receiver<Event>) {
+        let evt = evt_r.try_recv().expect("no reply to dropped command");
+        assert!(!evt.success);
+        assert_eq!(evt.error.unwrap().code, 503);
+    }
+
+    #[test]
+    fn reject_fails_when_full() {
+        let (dispatcher, queue) = dispatcher(1, OverflowPolicy::Reject);
+        assert!(submit(&dispatcher, 1).0.is_ok());
+        let (res, _) = submit(&dispatcher, 2);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        assert_eq!(dispatcher.queue_status().overflowed, 1);
+        assert_eq!(queued(&queue), ["capability-1"]);
+    }
+
+    #[test]
+    fn drop_newest_answers_the_new_command() {
+        let (dispatcher, queue) = dispatcher(1, OverflowPolicy::DropNewest);
+        let (res, first) = submit(&dispatcher, 1);
+        assert!(res.is_ok());
+        let (res, second) = submit(&dispatcher, 2);
+        assert!(res.is_ok());
+
+        assert_dropped(&second);
+        assert!(first.try_recv().is_err());
+        assert_eq!(dispatcher.queue_status().overflowed, 1);
+        assert_eq!(queued(&queue), ["capability-1"]);
+    }
+
+    #[test]
+    fn drop_oldest_answers_the_queued_command() {
+        let (dispatcher, queue) = dispatcher(2, OverflowPolicy::DropOldest);
+        let (_, first) = submit(&dispatcher, 1);
+        let (_, second) = submit(&dispatcher, 2);
+        let (res, third) = submit(&dispatcher, 3);
+        assert!(res.is_ok());
+
+        assert_dropped(&first);
+        assert!(second.try_recv().is_err());
+        assert!(third.try_recv().is_err());
+        assert_eq!(dispatcher.queue_status().overflowed, 1);
+        assert_eq!(queued(&queue), ["capability-2", "capability-3"]);
+    }
+
+    #[test]
+    fn block_times_out_when_full() {
+        let (dispatcher, queue) = dispatcher(1, OverflowPolicy::Block);
+        assert!(submit(&dispatcher, 1).0.is_ok());
+        let (res, _) = submit(&dispatcher, 2);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        assert_eq!(dispatcher.queue_status().overflowed, 0);
+        assert_eq!(queued(&queue), ["capability-1"]);
+    }
+
+    #[test]
+    fn async_dispatch_never_blocks() {
+        let options = QueueOptions {
+            capacity: Some(1),
+            policy: OverflowPolicy::Block,
+        };
+        let (dispatcher, queue) = WaxosuitDispatcher::new(&options, None);
+        assert!(submit(&dispatcher, 1).0.is_ok());
+
+        let err = dispatcher.dispatch_async(&command(2)).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(dispatcher.queue_status().overflowed, 1);
+        assert_eq!(queued(&queue), ["capability-1"]);
+    }
+
+    #[test]
+    fn async_dispatch_times_out() {
+        let (dispatcher, _queue) = dispatcher(1, OverflowPolicy::Reject);
+        let err = dispatcher.dispatch_async(&command(1)).wait().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(dispatcher.in_flight(), 0);
+    }
+
+    #[test]
+    fn async_dispatch_resolves_with_reply() {
+        let (dispatcher, queue) = dispatcher(1, OverflowPolicy::Reject);
+        let handle = dispatcher.dispatch_async(&command(1));
+        let (_, reply_to) = queue.requests.try_recv().unwrap();
+        reply_to.send(Event {
+            success: true,
+            ..Default::default()
+        });
+        assert!(handle.wait().unwrap().success);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let options = QueueOptions {
+            capacity: Some(1),
+            policy: OverflowPolicy::Block,
+        };
+        let (dispatcher, queue) = WaxosuitDispatcher::new(&options, None);
+        assert!(submit(&dispatcher, 1).0.is_ok());
+
+        let taker = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            queue.requests.recv().unwrap().0.source
+        });
+        assert!(submit(&dispatcher, 2).0.is_ok());
+        assert_eq!(taker.join().unwrap(), "capability-1");
+        assert_eq!(dispatcher.queue_status().depth, 1);
+    }
+
+    #[test]
+    fn closed_queue_is_not_connected() {
+        let (dispatcher, queue) = dispatcher(2, OverflowPolicy::Reject);
+        let (_, waiting) = submit(&dispatcher, 1);
+        queue.close();
+
+        assert!(waiting.recv().is_err());
+        let (res, _) = submit(&dispatcher, 2);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotConnected);
+        assert!(queued(&queue).is_empty());
+    }
+
+    #[test]
+    fn queue_status_reports_depth_and_capacity() {
+        let (dispatcher, _queue) = dispatcher(3, OverflowPolicy::Reject);
+        submit(&dispatcher, 1).0.unwrap();
+        submit(&dispatcher, 2).0.unwrap();
+
+        let status = dispatcher.queue_status();
+        assert_eq!(status.depth, 2);
+        assert_eq!(status.capacity, Some(3));
+        assert_eq!(status.overflowed, 0);
+    }
+}
@@ -35,6 +35,19 @@ pub enum ErrorKind {
     HostCallFailure(Box<dyn StdError>),
     HttpClientFailure(reqwest::Error),
     Json(serde_json::error::Error),
+    ModuleCache(String),
+    MemoryLimitExceeded(u32, u32),
+    UnboundedMemory(u32),
+    GuestPanic(String),
+    NoSuchCapability(String),
+    LiveUpdateRejected(String),
+    NoPreviousVersion,
+    NoCanary,
+    InvalidIssuerKey(String),
+    UntrustedIssuer(String),
+    GuestInterrupted,
+    Instrumentation(String),
+    ProviderConfiguration(Box<dyn StdError>),
 }
 
 impl Error {
@@ -61,6 +74,19 @@ impl StdError for Error {
             ErrorKind::HostCallFailure(_) => "Error occurred during host call",
             ErrorKind::HttpClientFailure(_) => "HTTP client error",
             ErrorKind::Json(_) => "JSON encoding/decoding failure",
+            ErrorKind::ModuleCache(_) => "Compiled module cache failure",
+            ErrorKind::MemoryLimitExceeded(_, _) => "Guest module exceeded its memory limit",
+            ErrorKind::UnboundedMemory(_) => "Guest module declares no maximum memory",
+            ErrorKind::GuestPanic(_) => "Guest module threw an exception",
+            ErrorKind::NoSuchCapability(_) => "No such capability provider loaded",
+            ErrorKind::LiveUpdateRejected(_) => "Live update rejected",
+            ErrorKind::NoPreviousVersion => "No previous module version to roll back to",
+            ErrorKind::NoCanary => "No canary module version is running",
+            ErrorKind::InvalidIssuerKey(_) => "Invalid issuer public key",
+            ErrorKind::UntrustedIssuer(_) => "Module issuer is not trusted",
+            ErrorKind::GuestInterrupted => "Guest call was interrupted",
+            ErrorKind::Instrumentation(_) => "Unable to instrument WebAssembly module",
+            ErrorKind::ProviderConfiguration(_) => "Capability provider failed to configure",
         }
     }
 
@@ -77,6 +103,19 @@ impl StdError for Error {
             ErrorKind::HostCallFailure(_) => None,
             ErrorKind::HttpClientFailure(ref err) => Some(err),
             ErrorKind::Json(ref err) => Some(err),
+            ErrorKind::ModuleCache(_) => None,
+            ErrorKind::MemoryLimitExceeded(_, _) => None,
+            ErrorKind::UnboundedMemory(_) => None,
+            ErrorKind::GuestPanic(_) => None,
+            ErrorKind::NoSuchCapability(_) => None,
+            ErrorKind::LiveUpdateRejected(_) => None,
+            ErrorKind::NoPreviousVersion => None,
+            ErrorKind::NoCanary => None,
+            ErrorKind::InvalidIssuerKey(_) => None,
+            ErrorKind::UntrustedIssuer(_) => None,
+            ErrorKind::GuestInterrupted => None,
+            ErrorKind::Instrumentation(_) => None,
+            ErrorKind::ProviderConfiguration(_) => None,
         }
     }
 }
@@ -102,6 +141,39 @@ impl fmt::Display for Error {
             }
             ErrorKind::HttpClientFailure(ref err) => write!(f, "HTTP client error: {}", err),
             ErrorKind::Json(ref err) => write!(f, "JSON error: {}", err),
+            ErrorKind::ModuleCache(ref err) => write!(f, "Compiled module cache error: {}", err),
+            ErrorKind::MemoryLimitExceeded(pages, limit) => write!(
+                f,
+                "Guest module memory of {} pages exceeds limit of {} pages",
+                pages, limit
+            ),
+            ErrorKind::UnboundedMemory(limit) => write!(
+                f,
+                "Guest module declares no maximum memory, must be at most {} pages",
+                limit
+            ),
+            ErrorKind::GuestPanic(ref msg) => write!(f, "Guest module threw an exception: {}", msg),
+            ErrorKind::NoSuchCapability(ref capid) => {
+                write!(f, "No capability provider loaded for {}", capid)
+            }
+            ErrorKind::LiveUpdateRejected(ref reason) => {
+                write!(f, "Live update rejected: {}", reason)
+            }
+            ErrorKind::NoPreviousVersion => {
+                write!(f, "No previous module version to roll back to")
+            }
+            ErrorKind::NoCanary => write!(f, "No canary module version is running"),
+            ErrorKind::InvalidIssuerKey(ref key) => write!(f, "Invalid issuer public key: {}", key),
+            ErrorKind::UntrustedIssuer(ref reason) => {
+                write!(f, "Module issuer is not trusted: {}", reason)
+            }
+            ErrorKind::GuestInterrupted => write!(f, "Guest call was interrupted"),
+            ErrorKind::Instrumentation(ref err) => {
+                write!(f, "Unable to instrument WebAssembly module: {}", err)
+            }
+            ErrorKind::ProviderConfiguration(ref err) => {
+                write!(f, "Capability provider failed to configure: {}", err)
+            }
         }
     }
 }
@@ -18,10 +18,13 @@ extern crate log;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod canary;
 pub mod capabilities;
 pub mod dispatch;
 pub mod errors;
 pub mod mux;
+pub mod remote;
+pub mod trust;
 pub mod wasm;
 
 pub type Result<T> = std::result::Result<T, errors::Error>;
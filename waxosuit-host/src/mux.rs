@@ -12,82 +12,739 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::wasm::ModuleHost;
+use crate::dispatch::{DispatchRequest, ReplyTo, RequestQueue};
+use crate::wasm::{is_live_update, InterruptHandle, ModuleHost};
 use crate::Result;
 use crossbeam::atomic::AtomicCell;
-use crossbeam_channel::{Receiver, Select, Sender};
+use crossbeam_channel as channel;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Select, Sender, TryRecvError};
 use std::collections::HashMap;
 use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::RwLock;
-use std::thread;
+use std::sync::{Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use wascap_codec as codec;
 use wascap_codec::core::{Command, Event};
 use wasmer_runtime::Instance;
 
-type ChannelPair = (Receiver<Command>, Sender<Event>);
+const DEFAULT_LIVE_UPDATE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A command bound for the guest, along with the capability that sent it and the
+/// channel on which the dispatch awaits the reply
+struct WorkItem {
+    capability: String,
+    cmd: Command,
+    reply_to: ReplyTo,
+}
+
+/// Changes to the set of capabilities the select loop listens to, made while it's running
+enum Control {
+    Register(String, Receiver<DispatchRequest>),
+    Deregister(String),
+    Stop,
+}
+
+/// A capability's queue of requests as seen by the select loop, along with its share
+/// of the guest's time
+struct Lane {
+    capability: String,
+    requests: Receiver<DispatchRequest>,
+    weight: i64,
+    credit: i64,
+}
+
+/// Chooses the next lane to take a request from, among those with requests waiting,
+/// using smooth weighted round-robin: every waiting lane earns its weight in credit, and
+/// the lane with the most credit is chosen and pays back the total earned
+fn next_lane(lanes: &mut [Lane]) -> Option<usize> {
+    let mut total = 0;
+    let mut chosen: Option<(usize, i64)> = None;
+
+    for (index, lane) in lanes.iter_mut().enumerate() {
+        if lane.requests.is_empty() {
+            continue;
+        }
+        lane.credit += lane.weight;
+        total += lane.weight;
+        if chosen.map_or(true, |(_, credit)| lane.credit > credit) {
+            chosen = Some((index, lane.credit));
+        }
+    }
+
+    chosen.map(|(index, _)| {
+        lanes[index].credit -= total;
+        index
+    })
+}
+
+/// Options controlling how the multiplexer executes commands against the guest module
+#[derive(Clone, Debug)]
+pub struct MuxOptions {
+    /// The number of guest module instances available to handle commands concurrently
+    pub pool_size: usize,
+    /// The maximum duration of a single guest call, unless overridden for a capability
+    pub call_timeout: Option<Duration>,
+    /// Per-capability overrides of `call_timeout`, keyed by capability ID
+    pub capability_timeouts: HashMap<String, Duration>,
+    /// The maximum duration of a live update, which isn't subject to `call_timeout` as it
+    /// covers compiling the new module and any health checks of it. Five minutes unless set
+    pub live_update_timeout: Option<Duration>,
+    /// Scheduling weights keyed by capability ID. When several capabilities have commands
+    /// waiting, each is handed to the guest in proportion to its weight, so no capability
+    /// is starved by another's backlog. Capabilities without a weight have a weight of 1
+    pub capability_weights: HashMap<String, u32>,
+}
+
+impl MuxOptions {
+    /// Whether any guest call has a timeout, which needs the guest module to be metered
+    /// for it to stop guest code
+    pub fn has_call_timeout(&self) -> bool {
+        self.call_timeout.is_some() || !self.capability_timeouts.is_empty()
+    }
+
+    fn timeout_for(&self, capability: &str) -> Option<Duration> {
+        self.capability_timeouts
+            .get(capability)
+            .cloned()
+            .or(self.call_timeout)
+    }
+
+    fn weight_for(&self, capability: &str) -> u32 {
+        self.capability_weights
+            .get(capability)
+            .cloned()
+            .unwrap_or(1)
+            .max(1)
+    }
+}
+
+impl Default for MuxOptions {
+    fn default() -> Self {
+        MuxOptions {
+            pool_size: 1,
+            call_timeout: None,
+            capability_timeouts: HashMap::new(),
+            live_update_timeout: Some(DEFAULT_LIVE_UPDATE_TIMEOUT),
+            capability_weights: HashMap::new(),
+        }
+    }
+}
 
 /// The multiplexer starts a thread that performs a `select` in an infinite loop, waiting for
-/// commands to come in from the various dispatchers being held by capability providers. For each
-/// command, the muxer will invoke the appropriate wasm function in the encapsulated wasm instance,
-/// and return the result on the appropriate response channel
+/// commands to come in from the various dispatchers being held by capability providers. Each
+/// command is handed to whichever instance in the pool of wasm instances is free, and that
+/// instance returns the result on the appropriate response channel. Capabilities may be
+/// registered and deregistered at any time; once the loop is running, changes reach it over
+/// a control channel that is part of its select set
 pub struct Multiplexer {
-    cap_channels: RwLock<HashMap<String, ChannelPair>>,
+    cap_channels: RwLock<HashMap<String, RequestQueue>>,
+    control_s: Sender<Control>,
+    control_r: Receiver<Control>,
     running: Arc<AtomicCell<bool>>,
+    stopped: AtomicCell<bool>,
+    draining: Arc<AtomicCell<bool>>,
+    in_flight: Arc<AtomicUsize>,
+    restarts: Arc<AtomicUsize>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl Multiplexer {
     pub fn new() -> Self {
+        let (control_s, control_r) = channel::unbounded();
         Multiplexer {
             cap_channels: RwLock::new(HashMap::new()),
+            control_s,
+            control_r,
             running: Arc::new(AtomicCell::new(false)),
+            stopped: AtomicCell::new(false),
+            draining: Arc::new(AtomicCell::new(false)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            restarts: Arc::new(AtomicUsize::new(0)),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stops the select loop. Commands already handed to a worker still complete, and
+    /// the workers exit once they have. Use `join` to wait for that. Commands still
+    /// waiting in a capability's queue, and any dispatched after this, fail with
+    /// `ErrorKind::NotConnected`
+    pub fn stop(&self) {
+        self.stopped.store(true);
+        if self.running.swap(false) {
+            info!("Stopping multiplexer");
+            // wakes the select loop if it's waiting
+            self.control_s.send(Control::Stop).unwrap();
+        }
+        for queue in self.cap_channels.read().unwrap().values() {
+            queue.close();
         }
     }
 
-    pub fn register_capability(
+    /// Waits for the select loop and worker threads to exit after `stop`
+    pub fn join(&self) {
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("Multiplexer thread panicked");
+            }
+        }
+    }
+
+    /// The number of times a guest module instance has been replaced, or a multiplexer
+    /// thread has recovered, after a failure
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting new commands, answering them with a failure event instead, and
+    /// waits up to the grace period for the commands already accepted to complete.
+    /// Returns `false` if commands were still in flight when the grace period ran out
+    pub fn drain(&self, grace: Duration) -> bool {
+        self.draining.store(true);
+        let deadline = Instant::now() + grace;
+
+        loop {
+            let in_flight = self.in_flight.load(Ordering::SeqCst);
+            if in_flight == 0 {
+                info!("Multiplexer drained");
+                return true;
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "Grace period expired with {} guest calls in flight",
+                    in_flight
+                );
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub(crate) fn register_capability(
         &self,
         cap_id: impl Into<String>,
-        commands_in: Receiver<Command>,
-        events_out: Sender<Event>,
+        requests_in: RequestQueue,
     ) -> Result<()> {
+        let cap_id = cap_id.into();
+        if self.stopped.load() {
+            // Nothing will ever take requests from this queue
+            requests_in.close();
+        }
+        let mut channels = self.cap_channels.write().unwrap();
+        if let Some(previous) = channels.insert(cap_id.clone(), requests_in.clone()) {
+            previous.close();
+        }
+        if self.running.load() {
+            self.control_s
+                .send(Control::Register(cap_id, requests_in.requests))
+                .unwrap();
+        }
+        Ok(())
+    }
+
+    /// Removes a capability's channels, so the select loop stops listening to it. Requests
+    /// still waiting in the capability's queue are not handed to the guest; they fail
+    /// with `ErrorKind::NotConnected`, as do any later dispatches to the queue
+    pub fn deregister_capability(&self, cap_id: &str) -> Result<()> {
         let mut channels = self.cap_channels.write().unwrap();
-        channels.insert(cap_id.into(), (commands_in, events_out));
+        if let Some(queue) = channels.remove(cap_id) {
+            queue.close();
+        }
+        if self.running.load() {
+            self.control_s
+                .send(Control::Deregister(cap_id.to_string()))
+                .unwrap();
+        }
         Ok(())
     }
 
-    pub fn run<F>(&self, host_factory: F) -> Result<()>
+    /// Starts the select loop and a pool of worker threads, each of which supervises
+    /// a `ModuleHost` created by the supplied factory
+    pub fn run<F>(&self, options: MuxOptions, host_factory: F) -> Result<()>
     where
         F: Fn() -> Result<ModuleHost> + Sync + Send,
         F: 'static,
     {
         let running = self.running.clone();
+        let draining = self.draining.clone();
+        let in_flight = self.in_flight.clone();
 
-        let channels: Vec<ChannelPair> = {
+        let options = Arc::new(options);
+        let control_r = self.control_r.clone();
+
+        // Registrations made from here on are sent over the control channel instead
+        let lanes: Vec<Lane> = {
             let lock = self.cap_channels.read().unwrap();
-            lock.iter().map(|(_, v)| v.clone()).collect()
+            running.store(true);
+            lock.iter()
+                .map(|(k, v)| new_lane(k.clone(), v.requests.clone(), &options))
+                .collect()
         };
 
-        running.store(true);
+        // A rendezvous channel, so requests stay in their capability's queue until a
+        // worker is free and the scheduler can choose between them
+        let (work_s, work_r) = channel::bounded::<WorkItem>(0);
+        let host_factory = Arc::new(host_factory);
+        let pool_size = options.pool_size.max(1);
 
-        thread::spawn(move || {
+        let mut threads = self.threads.lock().unwrap();
+        for _ in 0..pool_size {
+            threads.push(spawn_worker(
+                work_r.clone(),
+                options.clone(),
+                host_factory.clone(),
+                in_flight.clone(),
+                self.restarts.clone(),
+            ));
+        }
+        info!("Started guest module instance pool of {}", pool_size);
+
+        let mut select_loop = SelectLoop {
+            lanes,
+            control_r,
+            options,
+            work_s,
+            draining,
+            in_flight,
+        };
+        let restarts = self.restarts.clone();
+        // The select loop is the first thread joined, since the workers only exit after it
+        threads.insert(
+            0,
+            thread::spawn(move || {
+                while running.load() {
+                    match panic::catch_unwind(AssertUnwindSafe(|| select_loop.turn())) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(_) => {
+                            restarts.fetch_add(1, Ordering::SeqCst);
+                            error!("Multiplexer select loop panicked, resuming");
+                        }
+                    }
+                }
+                info!("Multiplexer stopped");
+            }),
+        );
+
+        Ok(())
+    }
+}
+
+/// The state of the select loop, which survives a panic in any one turn of the loop
+struct SelectLoop {
+    lanes: Vec<Lane>,
+    control_r: Receiver<Control>,
+    options: Arc<MuxOptions>,
+    work_s: Sender<WorkItem>,
+    draining: Arc<AtomicCell<bool>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl SelectLoop {
+    /// Waits for a control message or a request, and handles it. Returns `false` once
+    /// the loop has been told to stop
+    fn turn(&mut self) -> bool {
+        let ready = {
             let mut sel = Select::new();
-            for capability in channels.iter() {
-                sel.recv(&capability.0); // receiver - 0
+            sel.recv(&self.control_r);
+            for lane in self.lanes.iter() {
+                sel.recv(&lane.requests);
             }
+            // wait until a lane has a request waiting or has disconnected
+            sel.ready()
+        };
+        if ready == 0 {
+            for control in self.control_r.try_iter() {
+                match control {
+                    Control::Stop => return false,
+                    control => apply_control(&mut self.lanes, control, &self.options),
+                }
+            }
+            return true;
+        }
 
-            let mut modhost = (host_factory)().unwrap();
+        match next_lane(&mut self.lanes) {
+            Some(index) => {
+                // another consumer of the queue may have got there first
+                if let Ok(request) = self.lanes[index].requests.try_recv() {
+                    self.hand_off(&self.lanes[index].capability, request);
+                }
+            }
+            // a lane became ready without a request, so its provider is gone
+            None => {
+                let mut lanes = ::std::mem::replace(&mut self.lanes, Vec::new());
+                lanes.retain(|lane| match lane.requests.try_recv() {
+                    Ok(request) => {
+                        self.hand_off(&lane.capability, request);
+                        true
+                    }
+                    Err(TryRecvError::Empty) => true,
+                    Err(TryRecvError::Disconnected) => {
+                        info!(
+                            "Capability {} disconnected from multiplexer",
+                            lane.capability
+                        );
+                        false
+                    }
+                });
+                self.lanes = lanes;
+            }
+        }
+        true
+    }
 
-            while running.load() {
-                let oper = sel.select();
-                let index = oper.index();
-                let cmd = oper.recv(&channels[index].0);
-                // deliver command to the wasm module
-                if let Ok(cmd) = cmd {
-                    let result = modhost.call(&cmd).unwrap();
-                    channels[index].1.send(result).unwrap();
+    /// Hands a request to the next free wasm instance, or turns it away if the host is
+    /// shutting down
+    fn hand_off(&self, capability: &str, (cmd, reply_to): DispatchRequest) {
+        if self.draining.load() {
+            let _ = reply_to.send(failure_event(503, "Host is shutting down"));
+            return;
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let item = WorkItem {
+            capability: capability.to_string(),
+            cmd,
+            reply_to,
+        };
+        if let Err(e) = self.work_s.send(item) {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let _ = e
+                .into_inner()
+                .reply_to
+                .send(failure_event(500, "No guest module instances are running"));
+        }
+    }
+}
+
+fn new_lane(capability: String, requests: Receiver<DispatchRequest>, options: &MuxOptions) -> Lane {
+    Lane {
+        weight: options.weight_for(&capability) as i64,
+        capability,
+        requests,
+        credit: 0,
+    }
+}
+
+fn apply_control(lanes: &mut Vec<Lane>, control: Control, options: &MuxOptions) {
+    match control {
+        Control::Register(capability, requests) => {
+            info!(
+                "Capability {} registered with running multiplexer",
+                capability
+            );
+            lanes.retain(|lane| lane.capability != capability);
+            lanes.push(new_lane(capability, requests, options));
+        }
+        Control::Deregister(capability) => {
+            info!("Capability {} deregistered from multiplexer", capability);
+            lanes.retain(|lane| lane.capability != capability);
+        }
+        Control::Stop => {}
+    }
+}
+
+/// Starts a pool worker, which takes commands from the shared work queue and runs them
+/// on its executor. If a call exceeds its deadline, the caller receives a timeout event,
+/// the guest is interrupted and the executor (along with its instance) is replaced. The
+/// same happens if the instance fails or the worker panics, so a broken instance is
+/// never reused
+fn spawn_worker<F>(
+    work_r: Receiver<WorkItem>,
+    options: Arc<MuxOptions>,
+    host_factory: Arc<F>,
+    in_flight: Arc<AtomicUsize>,
+    restarts: Arc<AtomicUsize>,
+) -> JoinHandle<()>
+where
+    F: Fn() -> Result<ModuleHost> + Sync + Send,
+    F: 'static,
+{
+    thread::spawn(move || {
+        let mut executor = Executor::spawn(host_factory.clone());
+
+        for item in work_r.iter() {
+            let WorkItem {
+                capability,
+                cmd,
+                reply_to,
+            } = item;
+            let timeout = if is_live_update(&cmd) {
+                options.live_update_timeout
+            } else {
+                options.timeout_for(&capability)
+            };
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                let (result, healthy) = match executor.call(cmd, timeout) {
+                    Ok(evt) => (evt, true),
+                    Err(evt) => (evt, false),
+                };
+                // The dispatcher may have stopped waiting for this reply
+                let _ = reply_to.send(result);
+                healthy
+            }));
+            let healthy = outcome.unwrap_or_else(|_| {
+                error!("Worker panicked handling a command from {}", capability);
+                false
+            });
+            if !healthy {
+                warn!(
+                    "Recycling guest module instance after failed call from {}",
+                    capability
+                );
+                executor = Executor::spawn(host_factory.clone());
+                restarts.fetch_add(1, Ordering::SeqCst);
+            }
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    })
+}
+
+/// Owns a single guest module instance on a dedicated thread, so that the worker
+/// supervising it can stop waiting for a call that runs too long. Dropping the executor
+/// interrupts the guest, which then traps, and its thread exits
+struct Executor {
+    cmd_s: Sender<Command>,
+    evt_r: Receiver<Event>,
+    abandon: Arc<Mutex<Abandon>>,
+}
+
+/// Shared between an executor and its thread, so that an executor dropped before its
+/// instance has been created still stops the thread
+#[derive(Default)]
+struct Abandon {
+    abandoned: bool,
+    interrupt: Option<InterruptHandle>,
+}
+
+impl Executor {
+    fn spawn<F>(host_factory: Arc<F>) -> Executor
+    where
+        F: Fn() -> Result<ModuleHost> + Sync + Send,
+        F: 'static,
+    {
+        let (cmd_s, cmd_r) = channel::unbounded::<Command>();
+        let (evt_s, evt_r) = channel::bounded(1);
+        let abandon = Arc::new(Mutex::new(Abandon::default()));
+        let thread_abandon = abandon.clone();
+
+        thread::spawn(move || {
+            // Wasm instances can't move between threads, so each executor creates its own
+            let mut modhost = match (host_factory)() {
+                Ok(modhost) => modhost,
+                Err(e) => {
+                    error!("Failed to create guest module instance: {}", e);
+                    return;
+                }
+            };
+            {
+                let mut abandon = thread_abandon.lock().unwrap();
+                if abandon.abandoned {
+                    return;
+                }
+                abandon.interrupt = Some(modhost.interrupt_handle());
+            }
+
+            for cmd in cmd_r.iter() {
+                let evt = match modhost.call(&cmd) {
+                    Ok(evt) => evt,
+                    Err(e) => failure_event(500, format!("Guest call failure: {}", e)),
+                };
+                if evt_s.send(evt).is_err() {
+                    // The supervising worker gave up on this call
+                    break;
                 }
             }
         });
 
-        Ok(())
+        Executor {
+            cmd_s,
+            evt_r,
+            abandon,
+        }
+    }
+
+    /// Interrupts the guest and stops the executor's thread, returning whether the call it
+    /// was running had already committed a change
+    fn abandon(&self) -> bool {
+        let mut abandon = self.abandon.lock().unwrap();
+        abandon.abandoned = true;
+        match abandon.interrupt {
+            Some(ref interrupt) => interrupt.interrupt(),
+            None => false,
+        }
+    }
+
+    /// Runs the command on the guest, waiting no longer than the timeout for a reply. An
+    /// `Err` means this executor can no longer be used and the contained event should be
+    /// returned to the caller
+    fn call(&self, cmd: Command, timeout: Option<Duration>) -> ::std::result::Result<Event, Event> {
+        if self.cmd_s.send(cmd).is_err() {
+            return Err(failure_event(500, "Guest module instance is unavailable"));
+        }
+        match timeout {
+            Some(t) => match self.evt_r.recv_timeout(t) {
+                Ok(evt) => Ok(evt),
+                Err(RecvTimeoutError::Timeout) => {
+                    // Interrupted before the caller hears of the timeout, so that a change
+                    // the call commits, like installing a live update, is either reported
+                    // here or never made
+                    let description = if self.abandon() {
+                        format!(
+                            "Guest call timed out after {}ms, having already taken effect",
+                            t.as_millis()
+                        )
+                    } else {
+                        format!("Guest call timed out after {}ms", t.as_millis())
+                    };
+                    Err(failure_event(504, description))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    Err(failure_event(500, "Guest module instance terminated"))
+                }
+            },
+            None => self
+                .evt_r
+                .recv()
+                .map_err(|_| failure_event(500, "Guest module instance terminated")),
+        }
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+pub(crate) fn failure_event(code: u32, description: impl Into<String>) -> Event {
+    Event {
+        success: false,
+        payload: None,
+        error: Some(codec::core::Error {
+            code,
+            description: description.into(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lane(capability: &str, weight: i64, waiting: usize) -> Lane {
+        let (requests_s, requests) = channel::unbounded();
+        for _ in 0..waiting {
+            let (reply_s, _) = channel::bounded(1);
+            requests_s
+                .send((Command::default(), ReplyTo::Channel(reply_s)))
+                .unwrap();
+        }
+        Lane {
+            capability: capability.to_string(),
+            requests,
+            weight,
+            credit: 0,
+        }
+    }
+
+    /// Takes `n` requests the way the select loop does, returning the capabilities
+    /// they came from
+    fn take(lanes: &mut [Lane], n: usize) -> Vec<String> {
+        (0..n)
+            .filter_map(|_| {
+                let index = next_lane(lanes)?;
+                lanes[index].requests.try_recv().unwrap();
+                Some(lanes[index].capability.clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_lane_without_waiting_requests() {
+        let mut lanes = vec![
+            lane("wascap:http_server", 1, 0),
+            lane("wascap:messaging", 5, 0),
+        ];
+        assert_eq!(next_lane(&mut lanes), None);
+        assert_eq!(next_lane(&mut []), None);
+    }
+
+    #[test]
+    fn lanes_are_chosen_in_proportion_to_weight() {
+        let mut lanes = vec![
+            lane("wascap:http_server", 3, 20),
+            lane("wascap:messaging", 1, 20),
+        ];
+        let taken = take(&mut lanes, 8);
+
+        let http = taken.iter().filter(|c| *c == "wascap:http_server").count();
+        assert_eq!(http, 6);
+        assert_eq!(taken.len() - http, 2);
+        // Smooth round-robin interleaves the lanes rather than draining the heavier first
+        assert_eq!(
+            taken[..4],
+            [
+                "wascap:http_server",
+                "wascap:http_server",
+                "wascap:messaging",
+                "wascap:http_server"
+            ]
+        );
+    }
+
+    #[test]
+    fn equal_weights_alternate() {
+        let mut lanes = vec![
+            lane("wascap:http_server", 1, 3),
+            lane("wascap:messaging", 1, 3),
+        ];
+        assert_eq!(
+            take(&mut lanes, 4),
+            [
+                "wascap:http_server",
+                "wascap:messaging",
+                "wascap:http_server",
+                "wascap:messaging"
+            ]
+        );
+    }
+
+    #[test]
+    fn idle_lanes_earn_no_credit() {
+        let mut lanes = vec![
+            lane("wascap:http_server", 10, 0),
+            lane("wascap:messaging", 1, 3),
+        ];
+        assert_eq!(take(&mut lanes, 3).len(), 3);
+        // Otherwise the lane would be owed a burst of turns once its requests arrive
+        assert_eq!(lanes[0].credit, 0);
+        assert_eq!(lanes[1].credit, 0);
+    }
+
+    #[test]
+    fn exhausted_lane_is_skipped() {
+        let mut lanes = vec![
+            lane("wascap:http_server", 5, 1),
+            lane("wascap:messaging", 1, 3),
+        ];
+        assert_eq!(
+            take(&mut lanes, 4),
+            [
+                "wascap:http_server",
+                "wascap:messaging",
+                "wascap:messaging",
+                "wascap:messaging"
+            ]
+        );
+        assert_eq!(next_lane(&mut lanes), None);
     }
 }
@@ -0,0 +1,757 @@
+// Copyright 2015-2018 Capital One Services, LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Out-of-process capability providers. The host launches a provider executable as a
+//! child process and exchanges length-prefixed `Command` and `Event` frames with it over
+//! a Unix domain socket. On the host side the process is wrapped in a `RemoteProvider`,
+//! which looks like any other `CapabilityProvider` to the rest of the host and to the
+//! guest module. Inside the child process, `serve` runs an ordinary provider against
+//! that socket.
+
+use crate::mux::failure_event;
+use crossbeam_channel as channel;
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use prost::Message;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command as Process};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use wascap_codec::capabilities::{CapabilityProvider, Dispatcher, ModuleIdentity, NullDispatcher};
+use wascap_codec::core::{Command, Event};
+
+/// Environment variable holding the path of the socket a provider process connects to
+pub const ENV_PROVIDER_SOCKET: &'static str = "WAXOSUIT_PROVIDER_SOCKET";
+/// Environment variable holding the time, in milliseconds, a provider process waits for
+/// the guest module to answer a dispatch
+pub const ENV_PROVIDER_TIMEOUT: &'static str = "WAXOSUIT_PROVIDER_TIMEOUT";
+
+/// How long either side waits for a host call or dispatch to be answered, unless
+/// configured otherwise
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Frame kinds. Every frame is a little-endian u32 length, followed by the kind byte, a
+// little-endian u64 correlation ID and the encoded message. A reply carries the ID of
+// the frame it answers, so several host calls and dispatches can be outstanding at once.
+
+/// Provider -> host: a `Command` whose `target_cap` is the provider's capability ID
+/// and whose `source` is the provider's name
+const FRAME_HELLO: u8 = 1;
+/// Host -> provider: the JSON-encoded `ModuleIdentity` of the guest module
+const FRAME_CONFIGURE: u8 = 2;
+/// Host -> provider: a `Command` from the guest module
+const FRAME_HOST_CALL: u8 = 3;
+/// Provider -> host: the `Event` answering the host call with the same ID
+const FRAME_HOST_REPLY: u8 = 4;
+/// Provider -> host: a `Command` to be dispatched to the guest module
+const FRAME_DISPATCH: u8 = 5;
+/// Host -> provider: the guest module's `Event` answering the dispatch with the same ID
+const FRAME_DISPATCH_REPLY: u8 = 6;
+
+const FRAME_HEADER_LEN: usize = 1 + 8;
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+static SOCKET_COUNTER: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Senders for the replies awaited over a connection, by correlation ID
+type Pending = Arc<Mutex<HashMap<u64, Sender<Event>>>>;
+
+/// A capability provider running in a child process. The process is restarted if it
+/// exits while the provider is in use, and killed when the provider is dropped
+pub struct RemoteProvider {
+    capability_id: &'static str,
+    name: &'static str,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    program: PathBuf,
+    env: Vec<(String, String)>,
+    call_timeout: Duration,
+    dispatcher: RwLock<Box<dyn Dispatcher>>,
+    module_id: RwLock<Option<ModuleIdentity>>,
+    conn: Mutex<Option<Connection>>,
+    stopping: AtomicBool,
+}
+
+struct Connection {
+    child: Child,
+    writer: Arc<Mutex<UnixStream>>,
+    pending: Pending,
+}
+
+impl RemoteProvider {
+    /// Starts the provider executable, with the given environment variables set on top of
+    /// the host's, and waits for it to connect and identify itself. A restarted process
+    /// gets the same variables. Host calls, and the process's dispatches to the guest
+    /// module, fail if they're not answered within `call_timeout`
+    pub fn launch(
+        program: impl AsRef<Path>,
+        env: Vec<(String, String)>,
+        call_timeout: Duration,
+    ) -> io::Result<RemoteProvider> {
+        let inner = Arc::new(Inner {
+            program: program.as_ref().to_path_buf(),
+            env,
+            call_timeout,
+            dispatcher: RwLock::new(Box::new(NullDispatcher::new())),
+            module_id: RwLock::new(None),
+            conn: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+        });
+        let (conn, hello) = start_process(&inner)?;
+        *inner.conn.lock().unwrap() = Some(conn);
+
+        info!(
+            "Provider process {} connected for {}",
+            inner.program.display(),
+            hello.target_cap
+        );
+
+        // The provider trait hands out static strings, and this provider lives for
+        // (nearly) the life of the host process
+        Ok(RemoteProvider {
+            capability_id: Box::leak(hello.target_cap.into_boxed_str()),
+            name: Box::leak(hello.source.into_boxed_str()),
+            inner,
+        })
+    }
+}
+
+impl CapabilityProvider for RemoteProvider {
+    fn capability_id(&self) -> &'static str {
+        self.capability_id
+    }
+
+    fn configure_dispatch(
+        &self,
+        dispatcher: Box<dyn Dispatcher>,
+        module_id: ModuleIdentity,
+    ) -> Result<(), Box<dyn Error>> {
+        *self.inner.dispatcher.write().unwrap() = dispatcher;
+        *self.inner.module_id.write().unwrap() = Some(module_id.clone());
+
+        let lock = self.inner.conn.lock().unwrap();
+        match *lock {
+            Some(ref conn) => send_configure(&conn.writer, &module_id),
+            None => Err(Box::new(protocol_error("Provider process is not running"))),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn handle_call(&self, cmd: &Command) -> Result<Event, Box<dyn Error>> {
+        // The connection lock is only held long enough to find the connection, so host
+        // calls from every guest instance can be outstanding together
+        let (writer, pending) = match *self.inner.conn.lock().unwrap() {
+            Some(ref conn) => (conn.writer.clone(), conn.pending.clone()),
+            None => return Err(Box::new(protocol_error("Provider process is not running"))),
+        };
+        match round_trip(
+            &writer,
+            &pending,
+            FRAME_HOST_CALL,
+            cmd,
+            self.inner.call_timeout,
+        ) {
+            Ok(evt) => Ok(evt),
+            Err(e) => {
+                if e.kind() == io::ErrorKind::TimedOut {
+                    warn!(
+                        "Provider process for {} did not answer a host call within {}ms",
+                        self.capability_id,
+                        self.inner.call_timeout.as_millis()
+                    );
+                }
+                Err(Box::new(e))
+            }
+        }
+    }
+}
+
+impl Drop for RemoteProvider {
+    fn drop(&mut self) {
+        self.inner.stopping.store(true, Ordering::SeqCst);
+        if let Some(mut conn) = self.inner.conn.lock().unwrap().take() {
+            info!("Stopping provider process for {}", self.capability_id);
+            let _ = conn.child.kill();
+            let _ = conn.child.wait();
+        }
+    }
+}
+
+/// Spawns the provider process, accepts its connection and reads its hello. If the
+/// guest module has already been announced to this provider, it's announced again
+fn start_process(inner: &Arc<Inner>) -> io::Result<(Connection, Command)> {
+    let path = socket_path();
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+
+    let mut child = Process::new(&inner.program)
+        .envs(inner.env.iter().cloned())
+        .env(ENV_PROVIDER_SOCKET, &path)
+        .env(
+            ENV_PROVIDER_TIMEOUT,
+            inner.call_timeout.as_millis().to_string(),
+        )
+        .spawn()?;
+    let accepted = accept_provider(&listener, &mut child);
+    let _ = fs::remove_file(&path);
+    let stream = match accepted {
+        Ok(stream) => stream,
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+    };
+    stream.set_nonblocking(false)?;
+
+    let mut reader = stream.try_clone()?;
+    let hello = match read_frame(&mut reader)? {
+        (FRAME_HELLO, _, buf) => decode_command(&buf)?,
+        (kind, _, _) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(protocol_error(format!(
+                "Expected hello from provider process, got frame {}",
+                kind
+            )));
+        }
+    };
+
+    let writer = Arc::new(Mutex::new(stream));
+    if let Some(ref module_id) = *inner.module_id.read().unwrap() {
+        send_configure(&writer, module_id).map_err(|e| protocol_error(format!("{}", e)))?;
+    }
+
+    let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+    let reader_inner = inner.clone();
+    let reader_writer = writer.clone();
+    let reader_pending = pending.clone();
+    thread::spawn(move || read_loop(reader_inner, reader, reader_writer, reader_pending));
+
+    Ok((
+        Connection {
+            child,
+            writer,
+            pending,
+        },
+        hello,
+    ))
+}
+
+fn accept_provider(listener: &UnixListener, child: &mut Child) -> io::Result<UnixStream> {
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => return Ok(stream),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(status) = child.try_wait()? {
+                    return Err(protocol_error(format!(
+                        "Provider process exited before connecting: {}",
+                        status
+                    )));
+                }
+                if Instant::now() > deadline {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Provider process did not connect in time",
+                    ));
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads frames from the provider process until it disconnects, then restarts it
+/// unless the provider is being stopped
+fn read_loop(
+    inner: Arc<Inner>,
+    mut reader: UnixStream,
+    writer: Arc<Mutex<UnixStream>>,
+    pending: Pending,
+) {
+    loop {
+        match read_frame(&mut reader) {
+            Ok((FRAME_HOST_REPLY, id, buf)) => match decode_event(&buf) {
+                Ok(evt) => deliver(&pending, id, evt),
+                Err(e) => error!("Bad host call reply from provider process: {}", e),
+            },
+            Ok((FRAME_DISPATCH, id, buf)) => match decode_command(&buf) {
+                Ok(cmd) => {
+                    // Dispatching blocks until the guest replies, and the guest may make
+                    // host calls to this same provider in the meantime
+                    let inner = inner.clone();
+                    let writer = writer.clone();
+                    thread::spawn(move || {
+                        let result = {
+                            let lock = inner.dispatcher.read().unwrap();
+                            lock.dispatch(&cmd)
+                        };
+                        let evt = match result {
+                            Ok(evt) => evt,
+                            Err(e) => failure_event(500, format!("Dispatch failure: {}", e)),
+                        };
+                        if let Err(e) = write_message(&writer, FRAME_DISPATCH_REPLY, id, &evt) {
+                            error!("Failed to deliver dispatch reply to provider: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Bad dispatch from provider process: {}", e),
+            },
+            Ok((kind, _, _)) => warn!("Ignoring unexpected frame {} from provider process", kind),
+            Err(_) => break,
+        }
+    }
+    // Wakes up any host call waiting on this connection
+    pending.lock().unwrap().clear();
+
+    if !inner.stopping.load(Ordering::SeqCst) {
+        warn!(
+            "Provider process {} disconnected, restarting",
+            inner.program.display()
+        );
+        restart(&inner);
+    }
+}
+
+fn restart(inner: &Arc<Inner>) {
+    while !inner.stopping.load(Ordering::SeqCst) {
+        thread::sleep(RESTART_DELAY);
+        match start_process(inner) {
+            Ok((mut conn, hello)) => {
+                let mut lock = inner.conn.lock().unwrap();
+                if inner.stopping.load(Ordering::SeqCst) {
+                    let _ = conn.child.kill();
+                    let _ = conn.child.wait();
+                    return;
+                }
+                info!("Provider process for {} restarted", hello.target_cap);
+                if let Some(mut old) = lock.replace(conn) {
+                    let _ = old.child.kill();
+                    let _ = old.child.wait();
+                }
+                return;
+            }
+            Err(e) => error!(
+                "Failed to restart provider process {}: {}",
+                inner.program.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Runs a capability provider inside a process launched by the waxosuit host, relaying
+/// host calls and dispatches over the socket named by `WAXOSUIT_PROVIDER_SOCKET`. Returns
+/// once the host closes the connection
+pub fn serve(provider: Box<dyn CapabilityProvider>) -> io::Result<()> {
+    let path = std::env::var(ENV_PROVIDER_SOCKET)
+        .map_err(|_| protocol_error(format!("{} is not set", ENV_PROVIDER_SOCKET)))?;
+    let timeout = std::env::var(ENV_PROVIDER_TIMEOUT)
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_CALL_TIMEOUT);
+    let reader = UnixStream::connect(path)?;
+    let writer = Arc::new(Mutex::new(reader.try_clone()?));
+
+    let hello = Command {
+        source: provider.name().to_string(),
+        target_cap: provider.capability_id().to_string(),
+        ..Default::default()
+    };
+    write_message(&writer, FRAME_HELLO, 0, &hello)?;
+
+    let dispatcher = RemoteDispatcher {
+        writer: writer.clone(),
+        pending: Arc::new(Mutex::new(HashMap::new())),
+        timeout,
+    };
+    let result = relay(Arc::new(provider), reader, writer, &dispatcher);
+    // Wakes up any dispatch waiting on this connection
+    dispatcher.pending.lock().unwrap().clear();
+    result
+}
+
+/// Reads frames from the host until it closes the connection
+fn relay(
+    provider: Arc<Box<dyn CapabilityProvider>>,
+    mut reader: UnixStream,
+    writer: Arc<Mutex<UnixStream>>,
+    dispatcher: &RemoteDispatcher,
+) -> io::Result<()> {
+    loop {
+        let (kind, id, buf) = match read_frame(&mut reader) {
+            Ok(frame) => frame,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match kind {
+            FRAME_CONFIGURE => {
+                let module_id: ModuleIdentity = serde_json::from_slice(&buf)
+                    .map_err(|e| protocol_error(format!("Bad module identity: {}", e)))?;
+                if let Err(e) = provider.configure_dispatch(Box::new(dispatcher.clone()), module_id)
+                {
+                    error!("Provider failed to configure dispatch: {}", e);
+                }
+            }
+            FRAME_HOST_CALL => {
+                let cmd = decode_command(&buf)?;
+                let provider = provider.clone();
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    let evt = match provider.handle_call(&cmd) {
+                        Ok(evt) => evt,
+                        Err(e) => failure_event(500, format!("Host call failure: {}", e)),
+                    };
+                    if let Err(e) = write_message(&writer, FRAME_HOST_REPLY, id, &evt) {
+                        error!("Failed to deliver host call reply: {}", e);
+                    }
+                });
+            }
+            FRAME_DISPATCH_REPLY => deliver(&dispatcher.pending, id, decode_event(&buf)?),
+            kind => warn!("Ignoring unexpected frame {} from host", kind),
+        }
+    }
+}
+
+/// The dispatcher handed to a provider running inside a provider process
+#[derive(Clone)]
+struct RemoteDispatcher {
+    writer: Arc<Mutex<UnixStream>>,
+    pending: Pending,
+    timeout: Duration,
+}
+
+impl Dispatcher for RemoteDispatcher {
+    fn dispatch(&self, cmd: &Command) -> Result<Event, Box<dyn Error>> {
+        Ok(round_trip(
+            &self.writer,
+            &self.pending,
+            FRAME_DISPATCH,
+            cmd,
+            self.timeout,
+        )?)
+    }
+}
+
+/// Sends a command and waits up to `timeout` for the reply carrying its ID. The writer
+/// is only locked while the command is written, so replies to other commands can be
+/// awaited at the same time
+fn round_trip(
+    writer: &Arc<Mutex<UnixStream>>,
+    pending: &Pending,
+    kind: u8,
+    cmd: &Command,
+    timeout: Duration,
+) -> io::Result<Event> {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst) as u64;
+    let (reply_s, reply_r) = channel::bounded(1);
+    pending.lock().unwrap().insert(id, reply_s);
+
+    let result = write_message(writer, kind, id, cmd).and_then(|_| {
+        reply_r.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No reply within {}ms", timeout.as_millis()),
+            ),
+            RecvTimeoutError::Disconnected => protocol_error("Connection closed before reply"),
+        })
+    });
+    // A reply arriving after this has no one waiting for it, and is dropped
+    pending.lock().unwrap().remove(&id);
+    result
+}
+
+/// Hands a reply to the command waiting for it, if that command hasn't given up
+fn deliver(pending: &Pending, id: u64, evt: Event) {
+    if let Some(reply) = pending.lock().unwrap().remove(&id) {
+        let _ = reply.send(evt);
+    }
+}
+
+fn send_configure(
+    writer: &Arc<Mutex<UnixStream>>,
+    module_id: &ModuleIdentity,
+) -> Result<(), Box<dyn Error>> {
+    let buf = serde_json::to_vec(module_id)?;
+    let mut stream = writer.lock().unwrap();
+    write_frame(&mut *stream, FRAME_CONFIGURE, 0, &buf)?;
+    Ok(())
+}
+
+fn socket_path() -> PathBuf {
+    let n = SOCKET_COUNTER.fetch_add(1, Ordering::SeqCst);
+    std::env::temp_dir().join(format!("waxosuit-{}-{}.sock", std::process::id(), n))
+}
+
+fn write_message(
+    writer: &Arc<Mutex<UnixStream>>,
+    kind: u8,
+    id: u64,
+    msg: &impl prost::Message,
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut buf)
+        .map_err(|e| protocol_error(format!("{}", e)))?;
+    let mut stream = writer.lock().unwrap();
+    write_frame(&mut *stream, kind, id, &buf)
+}
+
+fn write_frame(w: &mut impl Write, kind: u8, id: u64, payload: &[u8]) -> io::Result<()> {
+    let len = (payload.len() + FRAME_HEADER_LEN) as u32;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&[kind])?;
+    w.write_all(&id.to_le_bytes())?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+fn read_frame(r: &mut impl Read) -> io::Result<(u8, u64, Vec<u8>)> {
+    let mut lenbuf = [0u8; 4];
+    r.read_exact(&mut lenbuf)?;
+    let len = u32::from_le_bytes(lenbuf) as usize;
+    if len < FRAME_HEADER_LEN || len > MAX_FRAME_LEN {
+        return Err(protocol_error(format!("Invalid frame length {}", len)));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    let payload = buf.split_off(FRAME_HEADER_LEN);
+    let mut idbuf = [0u8; 8];
+    idbuf.copy_from_slice(&buf[1..]);
+    Ok((buf[0], u64::from_le_bytes(idbuf), payload))
+}
+
+fn decode_command(buf: &[u8]) -> io::Result<Command> {
+    Command::decode(buf).map_err(|e| protocol_error(format!("{}", e)))
+}
+
+fn decode_event(buf: &[u8]) -> io::Result<Event> {
+    Event::decode(buf).map_err(|e| protocol_error(format!("{}", e)))
+}
+
+fn protocol_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn frame(len: u32, rest: &[u8]) -> Cursor<Vec<u8>> {
+        let mut buf = len.to_le_bytes().to_vec();
+        buf.extend_from_slice(rest);
+        Cursor::new(buf)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, FRAME_HOST_CALL, 7, b"payload").unwrap();
+        write_frame(&mut buf, FRAME_HOST_REPLY, u64::max_value(), &[]).unwrap();
+        assert_eq!(buf.len(), 4 + 9 + 7 + 4 + 9);
+
+        let mut r = Cursor::new(buf);
+        assert_eq!(
+            read_frame(&mut r).unwrap(),
+            (FRAME_HOST_CALL, 7, b"payload".to_vec())
+        );
+        assert_eq!(
+            read_frame(&mut r).unwrap(),
+            (FRAME_HOST_REPLY, u64::max_value(), Vec::new())
+        );
+        assert_eq!(
+            read_frame(&mut r).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let cmd = Command {
+            source: "wascap:messaging".to_string(),
+            target_cap: "guest".to_string(),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        cmd.encode(&mut buf).unwrap();
+        let mut framed = Vec::new();
+        write_frame(&mut framed, FRAME_DISPATCH, 3, &buf).unwrap();
+
+        let (kind, id, payload) = read_frame(&mut Cursor::new(framed)).unwrap();
+        assert_eq!(kind, FRAME_DISPATCH);
+        assert_eq!(id, 3);
+        let decoded = decode_command(&payload).unwrap();
+        assert_eq!(decoded.source, cmd.source);
+        assert_eq!(decoded.target_cap, cmd.target_cap);
+    }
+
+    #[test]
+    fn rejects_empty_frames() {
+        let err = read_frame(&mut frame(0, &[FRAME_HELLO])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_frames_without_an_id() {
+        let err = read_frame(&mut frame(1, &[FRAME_HELLO])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = read_frame(&mut frame(8, &[FRAME_HELLO, 0, 0, 0, 0, 0, 0, 0])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let err = read_frame(&mut frame(MAX_FRAME_LEN as u32 + 1, &[FRAME_HELLO])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn accepts_frames_at_the_limit() {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        buf[0] = FRAME_DISPATCH;
+        let (kind, _, payload) = read_frame(&mut frame(MAX_FRAME_LEN as u32, &buf)).unwrap();
+        assert_eq!(kind, FRAME_DISPATCH);
+        assert_eq!(payload.len(), MAX_FRAME_LEN - FRAME_HEADER_LEN);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let err = read_frame(&mut Cursor::new(vec![8, 0])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = read_frame(&mut frame(12, &[FRAME_HELLO, 1, 2])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    fn reply(code: u32) -> Event {
+        failure_event(code, "reply")
+    }
+
+    fn code(evt: &Event) -> u32 {
+        evt.error.as_ref().unwrap().code
+    }
+
+    #[test]
+    fn replies_are_matched_by_id() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let writer = Arc::new(Mutex::new(local.try_clone().unwrap()));
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+
+        let calls: Vec<_> = (0..2)
+            .map(|_| {
+                let writer = writer.clone();
+                let pending = pending.clone();
+                thread::spawn(move || {
+                    round_trip(
+                        &writer,
+                        &pending,
+                        FRAME_HOST_CALL,
+                        &Command::default(),
+                        Duration::from_secs(5),
+                    )
+                })
+            })
+            .collect();
+
+        // Both calls are outstanding at once, and are answered in reverse order
+        let (_, first, _) = read_frame(&mut remote).unwrap();
+        let (_, second, _) = read_frame(&mut remote).unwrap();
+        deliver(&pending, second, reply(second as u32));
+        deliver(&pending, first, reply(first as u32));
+
+        let mut codes: Vec<_> = calls
+            .into_iter()
+            .map(|call| code(&call.join().unwrap().unwrap()))
+            .collect();
+        codes.sort();
+        let mut ids = vec![first as u32, second as u32];
+        ids.sort();
+        assert_eq!(codes, ids);
+        assert!(pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unanswered_round_trips_time_out() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let writer = Arc::new(Mutex::new(local));
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+
+        let err = round_trip(
+            &writer,
+            &pending,
+            FRAME_DISPATCH,
+            &Command::default(),
+            Duration::from_millis(20),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(pending.lock().unwrap().is_empty());
+
+        // A late reply finds no one waiting and is dropped
+        let (_, id, _) = read_frame(&mut remote).unwrap();
+        deliver(&pending, id, reply(200));
+    }
+
+    #[test]
+    fn closed_connections_wake_round_trips() {
+        let (local, _remote) = UnixStream::pair().unwrap();
+        let writer = Arc::new(Mutex::new(local));
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+
+        let call = {
+            let pending = pending.clone();
+            thread::spawn(move || {
+                round_trip(
+                    &writer,
+                    &pending,
+                    FRAME_DISPATCH,
+                    &Command::default(),
+                    Duration::from_secs(5),
+                )
+            })
+        };
+        while pending.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        pending.lock().unwrap().clear();
+
+        let err = call.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn undecodable_payloads_are_protocol_errors() {
+        let err = decode_event(&[0xff, 0xff, 0xff]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2015-2018 Capital One Services, LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors;
+use crate::Result;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+const PUBLIC_KEY_LENGTH: usize = 56;
+const ACCOUNT_PREFIX: char = 'A';
+const OPERATOR_PREFIX: char = 'O';
+
+/// The public keys of the issuers whose modules may run. This is plain key pinning: an
+/// issuer is trusted because its key is listed, and no account or operator JWTs are
+/// verified. An account can be grouped under an operator key, in which case it's only
+/// trusted while that operator key is pinned too, so that removing an operator removes
+/// all of its accounts. The grouping is configuration, not something the keys prove.
+/// When no keys are pinned every issuer is trusted
+#[derive(Debug, Clone, Default)]
+pub struct PinnedIssuers {
+    accounts: HashMap<String, Option<String>>,
+    operators: HashSet<String>,
+}
+
+impl PinnedIssuers {
+    pub fn new() -> PinnedIssuers {
+        PinnedIssuers::default()
+    }
+
+    /// Trusts modules issued by the account, optionally only while the given operator
+    /// is pinned too
+    pub fn pin_account(&mut self, account: &str, operator: Option<&str>) -> Result<()> {
+        check_key(account, ACCOUNT_PREFIX)?;
+        if let Some(operator) = operator {
+            check_key(operator, OPERATOR_PREFIX)?;
+        }
+        self.accounts
+            .insert(account.to_string(), operator.map(|o| o.to_string()));
+        Ok(())
+    }
+
+    pub fn pin_operator(&mut self, operator: &str) -> Result<()> {
+        check_key(operator, OPERATOR_PREFIX)?;
+        self.operators.insert(operator.to_string());
+        Ok(())
+    }
+
+    /// Reads pinned keys from a file with one entry per line: an operator key, an
+    /// account key, or an account key followed by the key of its operator. Blank lines
+    /// and anything after a `#` are ignored
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let contents = fs::read_to_string(path)?;
+        for line in contents.lines() {
+            let line = line.splitn(2, '#').next().unwrap_or_default();
+            let keys: Vec<&str> = line.split_whitespace().collect();
+            match keys.as_slice() {
+                [] => {}
+                [key] if key.starts_with(OPERATOR_PREFIX) => self.pin_operator(key)?,
+                [account] => self.pin_account(account, None)?,
+                [account, operator] => self.pin_account(account, Some(operator))?,
+                _ => {
+                    return Err(errors::new(errors::ErrorKind::InvalidIssuerKey(
+                        line.trim().to_string(),
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty() && self.operators.is_empty()
+    }
+
+    /// Fails unless a module signed by the issuer may run
+    pub fn check(&self, issuer: &str) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        match self.accounts.get(issuer) {
+            Some(None) => Ok(()),
+            Some(Some(operator)) if self.operators.contains(operator) => Ok(()),
+            Some(Some(operator)) => Err(untrusted(format!(
+                "issuer {} is grouped under operator {}, which is not pinned",
+                issuer, operator
+            ))),
+            // Modules may also be signed with a pinned operator key directly
+            None if self.operators.contains(issuer) => Ok(()),
+            None => Err(untrusted(format!("issuer {} is not pinned", issuer))),
+        }
+    }
+}
+
+/// Public keys are upper case base32, prefixed with the kind of entity they belong to
+fn check_key(key: &str, prefix: char) -> Result<()> {
+    let valid = key.len() == PUBLIC_KEY_LENGTH
+        && key.starts_with(prefix)
+        && key
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || (c >= '2' && c <= '7'));
+    if valid {
+        Ok(())
+    } else {
+        Err(errors::new(errors::ErrorKind::InvalidIssuerKey(
+            key.to_string(),
+        )))
+    }
+}
+
+fn untrusted(reason: String) -> errors::Error {
+    errors::new(errors::ErrorKind::UntrustedIssuer(reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::ErrorKind;
+    use std::process;
+
+    fn key(prefix: char, fill: char) -> String {
+        let mut key = prefix.to_string();
+        key.extend(std::iter::repeat(fill).take(PUBLIC_KEY_LENGTH - 1));
+        key
+    }
+
+    fn is_untrusted(res: Result<()>) -> bool {
+        match res {
+            Err(e) => match e.kind() {
+                ErrorKind::UntrustedIssuer(_) => true,
+                _ => false,
+            },
+            Ok(()) => false,
+        }
+    }
+
+    fn is_invalid_key(res: Result<()>) -> bool {
+        match res {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidIssuerKey(_) => true,
+                _ => false,
+            },
+            Ok(()) => false,
+        }
+    }
+
+    fn load(name: &str, contents: &str) -> Result<PinnedIssuers> {
+        let path = std::env::temp_dir().join(format!("pinned-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let mut issuers = PinnedIssuers::new();
+        let res = issuers.load_file(&path);
+        fs::remove_file(&path).unwrap();
+        res.map(|_| issuers)
+    }
+
+    #[test]
+    fn empty_set_trusts_every_issuer() {
+        let issuers = PinnedIssuers::new();
+        assert!(issuers.check(&key('A', 'B')).is_ok());
+        assert!(issuers.check("anything").is_ok());
+    }
+
+    #[test]
+    fn pinned_account_is_trusted() {
+        let mut issuers = PinnedIssuers::new();
+        issuers.pin_account(&key('A', 'B'), None).unwrap();
+        assert!(issuers.check(&key('A', 'B')).is_ok());
+        assert!(is_untrusted(issuers.check(&key('A', 'C'))));
+    }
+
+    #[test]
+    fn grouped_account_needs_its_operator() {
+        let mut issuers = PinnedIssuers::new();
+        issuers
+            .pin_account(&key('A', 'B'), Some(&key('O', 'B')))
+            .unwrap();
+        assert!(is_untrusted(issuers.check(&key('A', 'B'))));
+
+        issuers.pin_operator(&key('O', 'C')).unwrap();
+        assert!(is_untrusted(issuers.check(&key('A', 'B'))));
+
+        issuers.pin_operator(&key('O', 'B')).unwrap();
+        assert!(issuers.check(&key('A', 'B')).is_ok());
+    }
+
+    #[test]
+    fn pinned_operator_may_sign_modules() {
+        let mut issuers = PinnedIssuers::new();
+        issuers.pin_operator(&key('O', 'B')).unwrap();
+        assert!(issuers.check(&key('O', 'B')).is_ok());
+        assert!(is_untrusted(issuers.check(&key('O', 'C'))));
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        let mut issuers = PinnedIssuers::new();
+        assert!(is_invalid_key(issuers.pin_account(&key('O', 'B'), None)));
+        assert!(is_invalid_key(issuers.pin_operator(&key('A', 'B'))));
+        assert!(is_invalid_key(issuers.pin_account(&key('A', 'b'), None)));
+        assert!(is_invalid_key(issuers.pin_account(&key('A', '8'), None)));
+        assert!(is_invalid_key(
+            issuers.pin_account(&key('A', 'B')[..PUBLIC_KEY_LENGTH - 1], None)
+        ));
+        assert!(is_invalid_key(
+            issuers.pin_account(&key('A', 'B'), Some(&key('A', 'C')))
+        ));
+        assert!(issuers.is_empty());
+    }
+
+    #[test]
+    fn loads_every_entry_form() {
+        let contents = format!(
+            "# pinned issuers\n\n{}\n  {}  # standalone account\n{} {}\n",
+            key('O', 'B'),
+            key('A', '2'),
+            key('A', '7'),
+            key('O', 'B')
+        );
+        let issuers = load("forms", &contents).unwrap();
+        assert!(issuers.check(&key('O', 'B')).is_ok());
+        assert!(issuers.check(&key('A', '2')).is_ok());
+        assert!(issuers.check(&key('A', '7')).is_ok());
+        assert!(is_untrusted(issuers.check(&key('A', 'C'))));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_pin_nothing() {
+        let issuers = load("comments", "# nothing here\n\n   \n# or here\n").unwrap();
+        assert!(issuers.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let three = format!("{} {} {}\n", key('A', 'B'), key('O', 'B'), key('O', 'C'));
+        assert!(is_invalid_key(load("three", &three).map(|_| ())));
+        assert!(is_invalid_key(load("short", "ABC\n").map(|_| ())));
+        let reversed = format!("{} {}\n", key('O', 'B'), key('A', 'B'));
+        assert!(is_invalid_key(load("reversed", &reversed).map(|_| ())));
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let mut issuers = PinnedIssuers::new();
+        let path = std::env::temp_dir().join(format!("pinned-{}-missing", process::id()));
+        assert!(issuers.load_file(path).is_err());
+    }
+}
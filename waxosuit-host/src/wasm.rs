@@ -12,15 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::capabilities::CAPMAN;
+use crate::canary::{Canary, CanaryOptions, Verdict};
+use crate::capabilities::{CapabilityManager, CAPMAN};
 use crate::errors;
+use crate::mux::failure_event;
 use crate::Result;
 use prost::Message;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use wascap::jwt::Claims;
 use wascap_codec as codec;
 use wascap_codec::core::{Command, Event};
-use wasmer_runtime::{error, func, imports, instantiate, Ctx, Func, Instance, Memory, Value};
+use wascap_codec::AsCommand;
+use wasmer_runtime::{
+    compile, default_compiler, error, func, imports, Ctx, Func, ImportObject, Instance, Memory,
+    Value,
+};
+use wasmer_runtime_core::cache::Artifact;
 use wasmer_runtime_core::Module;
 
 const HOST_NAMESPACE: &'static str = "wascap";
@@ -28,60 +44,670 @@ const HOST_THROW: &'static str = "__throw";
 const HOST_CONSOLE_LOG: &'static str = "__console_log";
 const HOST_CALL: &'static str = "__host_call";
 
+/// Where a metered guest module imports its metering hook from. `pwasm_utils` always
+/// injects the import into `env`, alongside imports a guest toolchain may have emitted
+/// itself, so the injected import is moved to the host's own namespace
+const INJECTED_NAMESPACE: &'static str = "env";
+const METERING_NAMESPACE: &'static str = "waxosuit";
+const METERING_GAS: &'static str = "gas";
+
 const GUEST_FREE: &'static str = "__wascap_free";
 const GUEST_MALLOC: &'static str = "__wascap_malloc";
 const GUEST_REALLOC: &'static str = "__wascap_realloc";
 const GUEST_CALL: &'static str = "__guest_call";
 const GUEST_GLOBAL_ARGUMENT_POINTER: &'static str = "__wascap_global_argument_ptr";
 
-pub struct ModuleHost {
-    // TODO: make this a pool of instances so we can deliver dispatch messages
-    // round-robin to them
+const WASM_PAGE_SIZE: usize = 65536;
+
+/// Each cached artifact is preceded by a digest of it, keyed with the cache's key
+const CACHE_DIGEST_LENGTH: usize = 64;
+const CACHE_KEY_LENGTH: usize = 32;
+const CACHE_KEY_FILE: &'static str = ".cache-key";
+
+/// The source of the health checks the host sends to a newly swapped-in module
+const HEALTH_CHECK_SOURCE: &'static str = "waxosuit:host";
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Stops the guest call running on an instance, so that a call which has run past its
+/// deadline doesn't keep its thread busy or go on making host calls. The guest traps at
+/// the next host call it makes or, if the module is metered, at the next block it enters,
+/// as every block of a metered module is instrumented with a call to the host's
+/// metering hook
+///
+/// Work other than guest code, like installing a live update, can't be stopped this way,
+/// so a call makes such changes through `commit`, which an interrupt can't overtake
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<Interrupt>);
+
+struct Interrupt {
+    interrupted: AtomicBool,
+    /// Whether the current call has committed a change, held while it does so
+    committed: Mutex<bool>,
+}
+
+impl InterruptHandle {
+    fn new() -> InterruptHandle {
+        InterruptHandle(Arc::new(Interrupt {
+            interrupted: AtomicBool::new(false),
+            committed: Mutex::new(false),
+        }))
+    }
+
+    /// Interrupts the call, returning whether it had already committed a change
+    pub fn interrupt(&self) -> bool {
+        let committed = self.0.committed.lock().unwrap();
+        self.0.interrupted.store(true, Ordering::SeqCst);
+        *committed
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.interrupted.load(Ordering::SeqCst)
+    }
+
+    /// Makes a change on behalf of the current call, unless it has been interrupted. The
+    /// change is either made before an interrupt arrives or not at all
+    fn commit<T>(&self, change: impl FnOnce() -> T) -> Result<T> {
+        let mut committed = self.0.committed.lock().unwrap();
+        if self.is_interrupted() {
+            return Err(errors::new(errors::ErrorKind::GuestInterrupted));
+        }
+        let result = change();
+        *committed = true;
+        Ok(result)
+    }
+
+    /// Starts a call that hasn't committed anything yet
+    fn begin(&self) {
+        *self.0.committed.lock().unwrap() = false;
+    }
+}
+
+/// What host functions reach through an instance's context data
+struct GuestContext {
+    capabilities: Arc<RwLock<CapabilityManager>>,
+    interrupt: InterruptHandle,
+}
+
+/// An instance along with the context its host functions use, which is dropped after it
+struct GuestInstance {
     instance: Instance,
+    _context: Box<GuestContext>,
 }
 
-impl ModuleHost {
-    pub fn new(buf: &[u8]) -> Result<ModuleHost> {
-        let import_object = imports! {
-            HOST_NAMESPACE => {
-                HOST_CONSOLE_LOG => func!(console_log),
-                HOST_THROW => func!(throw),
-                HOST_CALL => func!(host_call),
-            },
+/// A version of a guest module that has been live at some point
+#[derive(Clone)]
+pub struct ModuleVersion {
+    pub generation: u64,
+    pub hash: String,
+    pub issuer: String,
+    /// When this version went live
+    pub timestamp: SystemTime,
+    module: Module,
+    claims: Option<Claims>,
+}
+
+impl ModuleVersion {
+    fn new(generation: u64, module: Module, claims: Option<Claims>) -> ModuleVersion {
+        ModuleVersion {
+            generation,
+            hash: claims
+                .as_ref()
+                .map_or("".to_string(), |c| c.module_hash.clone()),
+            issuer: claims.as_ref().map_or("".to_string(), |c| c.issuer.clone()),
+            timestamp: SystemTime::now(),
+            module,
+            claims,
+        }
+    }
+}
+
+/// A compiled guest module, shared by every instance in a pool. Replacing the module
+/// bumps its generation, which causes each `ModuleHost` created from it to re-instantiate
+/// before handling its next command. Host calls from its instances go to the capability
+/// manager it's bound to, which is `CAPMAN` unless another is given.
+///
+/// Previous versions are kept so a live update can be rolled back, either on request or
+/// automatically when the new version fails its health check. With canary options set,
+/// a live update first runs alongside the current version and only replaces it once it
+/// has handled its share of commands without too many failures
+pub struct GuestModule {
+    current: RwLock<(u64, Module)>,
+    metered: bool,
+    memory_limit: Option<u32>,
+    capabilities: Arc<RwLock<CapabilityManager>>,
+    versions: Mutex<VecDeque<ModuleVersion>>,
+    history_size: usize,
+    health_grace: Option<Duration>,
+    canary_options: Option<CanaryOptions>,
+    canary: RwLock<Option<Arc<Canary>>>,
+    canaries: AtomicUsize,
+}
+
+impl GuestModule {
+    /// Compiles the module, and its live updates, metered if `metered` is set. Metering
+    /// lets a call timeout stop guest code that never makes a host call, at the cost of
+    /// a host function call on every block the guest enters, so it's only worth having
+    /// when guest calls have a timeout
+    pub fn new(buf: &[u8], metered: bool) -> Result<GuestModule> {
+        Ok(GuestModule::from_compiled(
+            compile_module(buf, metered)?,
+            metered,
+        ))
+    }
+
+    fn from_compiled(module: Module, metered: bool) -> GuestModule {
+        GuestModule {
+            current: RwLock::new((0, module.clone())),
+            metered,
+            memory_limit: None,
+            capabilities: CAPMAN.clone(),
+            versions: Mutex::new(vec![ModuleVersion::new(0, module, None)].into()),
+            history_size: 3,
+            health_grace: None,
+            canary_options: None,
+            canary: RwLock::new(None),
+            canaries: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records the claims of the module as it was started, so a rollback to this version
+    /// restores them
+    pub fn with_claims(self, claims: Claims) -> GuestModule {
+        {
+            let mut versions = self.versions.lock().unwrap();
+            if let Some(initial) = versions.back_mut() {
+                *initial =
+                    ModuleVersion::new(initial.generation, initial.module.clone(), Some(claims));
+            }
+        }
+        self
+    }
+
+    /// Sets how many previous versions are kept for rollback
+    pub fn keep_history(mut self, versions: usize) -> GuestModule {
+        self.history_size = versions;
+        self
+    }
+
+    /// After a live update, sends health checks to the new version for up to the grace
+    /// period, rolling back to the previous version if none of them succeed. The checks
+    /// run before the update is answered, so its reply can take as long as the grace period
+    pub fn check_health(mut self, grace: Option<Duration>) -> GuestModule {
+        self.health_grace = grace;
+        self
+    }
+
+    /// Trials live updates as canaries alongside the current version rather than
+    /// swapping them in for every command at once
+    pub fn with_canary(mut self, options: Option<CanaryOptions>) -> GuestModule {
+        self.canary_options = options;
+        self
+    }
+
+    /// Sets the maximum number of 64KiB pages of linear memory each instance may use.
+    /// Modules must declare a maximum memory size within this, which the runtime enforces
+    /// on every `memory.grow`; modules declaring no maximum, or a larger one, are refused
+    pub fn limit_memory(mut self, max_pages: Option<u32>) -> GuestModule {
+        self.memory_limit = max_pages;
+        self
+    }
+
+    /// Sends host calls from this module's instances to the given capability manager, so
+    /// that several modules in one process each have their own claims and providers
+    pub fn bind_capabilities(
+        mut self,
+        capabilities: Arc<RwLock<CapabilityManager>>,
+    ) -> GuestModule {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// The maximum number of pages of linear memory each instance may use, if limited
+    pub fn memory_limit(&self) -> Option<u32> {
+        self.memory_limit
+    }
+
+    /// Loads the compiled module for the given module hash from the cache. If it isn't
+    /// there (or can't be loaded), the buffer is compiled and the compiled artifact is
+    /// written to the cache so the next start of this module skips compilation. Metered
+    /// and unmetered compilations of a module are cached separately
+    pub fn with_cache(
+        buf: &[u8],
+        cache: &ModuleCache,
+        module_hash: &str,
+        metered: bool,
+    ) -> Result<GuestModule> {
+        let mut key: String = module_hash
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect();
+        if metered {
+            key.push_str("-metered");
+        }
+        let path = cache.dir.join(format!("{}.wasmer", key));
+
+        let module = match cache.load(&path, &key) {
+            Ok(module) => {
+                info!("Loaded compiled module from cache {}", path.display());
+                module
+            }
+            Err(e) => {
+                info!("No usable compiled module in cache ({}), compiling", e);
+                let module = compile_module(buf, metered)?;
+                if let Err(e) = cache.store(&path, &key, &module) {
+                    warn!("Failed to write compiled module to cache: {}", e);
+                }
+                module
+            }
         };
 
-        let mh = ModuleHost {
-            instance: instantiate(&buf, &import_object)?,
+        Ok(GuestModule::from_compiled(module, metered))
+    }
+
+    /// The generation of the compiled module, incremented on every replacement
+    pub fn generation(&self) -> u64 {
+        self.current.read().unwrap().0
+    }
+
+    /// The live version followed by the previous versions, most recent first
+    pub fn history(&self) -> Vec<ModuleVersion> {
+        self.versions
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Compiles the new buffer and makes it the current module for all instances, with
+    /// its claims applied to the module's capabilities
+    pub fn update(&self, buf: &[u8], claims: Claims) -> Result<u64> {
+        let module = self.prepare(buf)?;
+        Ok(self.install_version(module, claims))
+    }
+
+    /// Compiles a new version of the module, refusing it if it breaks the memory limit
+    fn prepare(&self, buf: &[u8]) -> Result<Module> {
+        let module = compile_module(buf, self.metered)?;
+        self.check_declared_memory(&module)?;
+        Ok(module)
+    }
+
+    fn install_version(&self, module: Module, claims: Claims) -> u64 {
+        let generation = {
+            let mut versions = self.versions.lock().unwrap();
+            let generation = self.install(module.clone());
+            versions.push_back(ModuleVersion::new(generation, module, Some(claims.clone())));
+            while versions.len() > self.history_size + 1 {
+                versions.pop_front();
+            }
+            generation
         };
+        self.capabilities.write().unwrap().apply_update(claims);
+        generation
+    }
 
-        Ok(mh)
+    /// Starts routing a share of commands to a new version, replacing any canary already
+    /// running. The new version's claims take effect once it's promoted. Returns the
+    /// canary's serial number
+    fn start_canary(&self, module: Module, claims: Claims) -> u64 {
+        let serial = self.canaries.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+        let options = self.canary_options.clone().unwrap_or_default();
+        let previous = self
+            .canary
+            .write()
+            .unwrap()
+            .replace(Arc::new(Canary::new(serial, module, claims, options)));
+        if let Some(previous) = previous {
+            warn!(
+                "Canary {} superseded by canary {} before it was judged",
+                previous.serial, serial
+            );
+        }
+        serial
+    }
+
+    /// The canary currently running alongside the module, if any
+    fn canary(&self) -> Option<Arc<Canary>> {
+        self.canary.read().unwrap().clone()
+    }
+
+    /// Makes the running canary the current module for all instances, with its claims
+    /// applied to the module's capabilities
+    pub fn promote_canary(&self) -> Result<u64> {
+        match self.canary.write().unwrap().take() {
+            Some(canary) => Ok(self.promote(&canary)),
+            None => Err(errors::new(errors::ErrorKind::NoCanary)),
+        }
+    }
+
+    /// Stops routing commands to the running canary and discards it
+    pub fn abort_canary(&self) -> Result<()> {
+        match self.canary.write().unwrap().take() {
+            Some(canary) => {
+                self.abort(&canary);
+                Ok(())
+            }
+            None => Err(errors::new(errors::ErrorKind::NoCanary)),
+        }
+    }
+
+    fn promote(&self, canary: &Canary) -> u64 {
+        let (calls, failures) = canary.stats();
+        let generation = self.install_version(canary.module.clone(), canary.claims.clone());
+        info!(
+            "Promoted canary {} to module generation {} after {} calls, {} failed",
+            canary.serial, generation, calls, failures
+        );
+        generation
+    }
+
+    fn abort(&self, canary: &Canary) {
+        let (calls, failures) = canary.stats();
+        warn!(
+            "Aborted canary {} (module hash {}) after {} calls, {} failed",
+            canary.serial, canary.claims.module_hash, calls, failures
+        );
+    }
+
+    /// Records the outcome of a call handled by a canary, promoting or aborting it once
+    /// it has been judged. Several instances may reach a verdict at once, but only the
+    /// first acts on it
+    fn judge(&self, canary: &Canary, success: bool) {
+        let verdict = canary.record(success);
+        if verdict == Verdict::Pending {
+            return;
+        }
+        let concluded = {
+            let mut lock = self.canary.write().unwrap();
+            match *lock {
+                Some(ref running) if running.serial == canary.serial => lock.take(),
+                _ => None,
+            }
+        };
+        if let Some(canary) = concluded {
+            match verdict {
+                Verdict::Promote => {
+                    self.promote(&canary);
+                }
+                _ => self.abort(&canary),
+            }
+        }
+    }
+
+    /// Discards the live version and makes the one before it current again, restoring
+    /// its claims. Returns the generation under which the restored version now runs
+    pub fn rollback(&self) -> Result<u64> {
+        let (generation, claims) = {
+            let mut versions = self.versions.lock().unwrap();
+            if versions.len() < 2 {
+                return Err(errors::new(errors::ErrorKind::NoPreviousVersion));
+            }
+            let discarded = versions.pop_back().unwrap();
+            let restored = versions.back_mut().unwrap();
+            restored.generation = self.install(restored.module.clone());
+            restored.timestamp = SystemTime::now();
+            warn!(
+                "Rolled back module generation {} (hash {}) to module hash {}",
+                discarded.generation, discarded.hash, restored.hash
+            );
+            (restored.generation, restored.claims.clone())
+        };
+        if let Some(claims) = claims {
+            self.capabilities.write().unwrap().apply_update(claims);
+        }
+        Ok(generation)
+    }
+
+    /// Makes the module current under a new generation
+    fn install(&self, module: Module) -> u64 {
+        let mut lock = self.current.write().unwrap();
+        lock.0 += 1;
+        lock.1 = module;
+        lock.0
+    }
+
+    fn instantiate(&self, interrupt: &InterruptHandle) -> Result<(u64, GuestInstance)> {
+        let (generation, module) = {
+            let lock = self.current.read().unwrap();
+            (lock.0, lock.1.clone())
+        };
+        Ok((generation, self.instantiate_module(&module, interrupt)?))
+    }
+
+    fn instantiate_module(
+        &self,
+        module: &Module,
+        interrupt: &InterruptHandle,
+    ) -> Result<GuestInstance> {
+        self.check_declared_memory(module)?;
+        let mut instance = module.instantiate(&import_object())?;
+        let context = Box::new(GuestContext {
+            capabilities: self.capabilities.clone(),
+            interrupt: interrupt.clone(),
+        });
+        instance.context_mut().data = &*context as *const GuestContext as *mut c_void;
+        Ok(GuestInstance {
+            instance,
+            _context: context,
+        })
+    }
+
+    /// Refuses a module whose memory could grow past the limit. The declared maximum is
+    /// what the runtime holds `memory.grow` to, so it has to be present and within the limit
+    fn check_declared_memory(&self, module: &Module) -> Result<()> {
+        if let Some(limit) = self.memory_limit {
+            for (_, desc) in module.info().memories.iter() {
+                if desc.minimum.0 > limit {
+                    return Err(errors::new(errors::ErrorKind::MemoryLimitExceeded(
+                        desc.minimum.0,
+                        limit,
+                    )));
+                }
+                match desc.maximum {
+                    None => {
+                        return Err(errors::new(errors::ErrorKind::UnboundedMemory(limit)));
+                    }
+                    Some(maximum) if maximum.0 > limit => {
+                        return Err(errors::new(errors::ErrorKind::MemoryLimitExceeded(
+                            maximum.0, limit,
+                        )));
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single instance of a guest module. The multiplexer keeps a pool of these, all
+/// created from the same `GuestModule`, along with an instance of its canary while
+/// one is running
+pub struct ModuleHost {
+    module: Arc<GuestModule>,
+    generation: u64,
+    instance: GuestInstance,
+    canary: Option<(u64, GuestInstance)>,
+    interrupt: InterruptHandle,
+}
+
+impl ModuleHost {
+    /// Compiles an unmetered module for a single instance
+    pub fn new(buf: &[u8]) -> Result<ModuleHost> {
+        ModuleHost::from_module(Arc::new(GuestModule::new(buf, false)?))
+    }
+
+    /// Creates a new instance of an already compiled guest module
+    pub fn from_module(module: Arc<GuestModule>) -> Result<ModuleHost> {
+        let interrupt = InterruptHandle::new();
+        let (generation, instance) = module.instantiate(&interrupt)?;
+
+        Ok(ModuleHost {
+            module,
+            generation,
+            instance,
+            canary: None,
+            interrupt,
+        })
+    }
+
+    /// A handle that stops whatever call this host is running, and fails every call
+    /// after it. Once interrupted, the host should be dropped
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
     }
 
     /// Invokes the __guest_call function within the guest module instance
     /// by encoding the command and decoding the result as an `Event`
     pub fn call(&mut self, cmd: &Command) -> Result<Event> {
+        if self.interrupt.is_interrupted() {
+            return Err(errors::new(errors::ErrorKind::GuestInterrupted));
+        }
+        self.interrupt.begin();
         if is_live_update(cmd) {
             return self.swap_module(cmd);
         }
-        let ptr = pass_message_to_wasm(&mut self.instance, cmd)?;
-        let lenresult = self.guest_call_fn()?.call(ptr, cmd.encoded_len() as i32)?;
+        self.refresh()?;
+        if let Some(canary) = self.module.canary() {
+            if canary.routes(cmd) {
+                let result = self.call_canary(&canary, cmd);
+                let success = result.as_ref().map_or(false, |evt| evt.success);
+                self.module.judge(&canary, success);
+                return result;
+            }
+        }
+        self.call_instance(false, cmd)
+    }
 
-        let resvec = self.get_vec_at_gp(lenresult);
+    fn call_canary(&mut self, canary: &Canary, cmd: &Command) -> Result<Event> {
+        let current = match self.canary {
+            Some((serial, _)) => serial == canary.serial,
+            None => false,
+        };
+        if !current {
+            let instance = self
+                .module
+                .instantiate_module(&canary.module, &self.interrupt)?;
+            self.canary = Some((canary.serial, instance));
+        }
+        self.call_instance(true, cmd)
+    }
+
+    fn call_instance(&mut self, canary: bool, cmd: &Command) -> Result<Event> {
+        let ptr = pass_message_to_wasm(self.instance(canary), cmd)?;
+        let callresult = self
+            .guest_call_fn(canary)?
+            .call(ptr, cmd.encoded_len() as i32);
+        let lenresult = match callresult {
+            Ok(len) => len,
+            Err(e) => {
+                // An interrupted host is about to be dropped, so there's no point replacing
+                // the instance. Otherwise a trapped instance can't be trusted to be in a
+                // consistent state
+                if !self.interrupt.is_interrupted() {
+                    self.reset(canary)?;
+                }
+                return Err(trap_error(e));
+            }
+        };
+
+        self.check_memory(canary)?;
+
+        let resvec = get_vec_from_wasm_gp(self.instance(canary), lenresult);
         let res_event = codec::core::Event::decode(&resvec)?;
 
         Ok(res_event)
     }
 
+    /// The canary instance when asked for and one exists, otherwise the current one
+    fn instance(&mut self, canary: bool) -> &mut Instance {
+        match (canary, &mut self.canary) {
+            (true, Some((_, guest))) => &mut guest.instance,
+            _ => &mut self.instance.instance,
+        }
+    }
+
+    /// Replaces the instance with a fresh one. A canary instance is simply dropped, and
+    /// recreated if the canary is still running when it's next needed
+    fn reset(&mut self, canary: bool) -> Result<()> {
+        if canary {
+            self.canary = None;
+            Ok(())
+        } else {
+            self.reinstantiate()
+        }
+    }
+
+    /// Fails the current call and replaces the instance with a fresh one if the guest
+    /// has grown its linear memory past the module's limit. Only a backstop, as modules
+    /// are refused unless their declared maximum already keeps them within it
+    fn check_memory(&mut self, canary: bool) -> Result<()> {
+        if let Some(limit) = self.module.memory_limit() {
+            let pages = (self.instance(canary).context().memory(0).view::<u8>().len()
+                / WASM_PAGE_SIZE) as u32;
+            if pages > limit {
+                warn!(
+                    "Guest memory grew to {} pages, exceeding limit of {}. Re-instantiating.",
+                    pages, limit
+                );
+                self.reset(canary)?;
+                return Err(errors::new(errors::ErrorKind::MemoryLimitExceeded(
+                    pages, limit,
+                )));
+            }
+        }
+        Ok(())
+    }
+
     fn swap_module(&mut self, cmd: &Command) -> Result<Event> {
         match cmd.payload {
             Some(ref p) => {
                 let hotswap = codec::core::LiveUpdate::decode(&p.value)?;
+                let verified = {
+                    let lock = self.module.capabilities.read().unwrap();
+                    lock.verify_update(&hotswap.new_module)
+                };
+                let claims = match verified {
+                    Ok(claims) => claims,
+                    // Reported to whoever submitted the update; the running module is fine
+                    Err(e) => return Ok(failure_event(403, format!("{}", e))),
+                };
                 info!(
                     "HOT SWAP - Replacing existing WebAssembly module with new buffer, {} bytes",
                     hotswap.new_module.len()
                 );
-                self.instance = create_instance_from_buf(&hotswap.new_module)?;
-                info!("HOT SWAP - Success");
+                // The multiplexer gives up on a live update that takes too long, so it's
+                // only installed if that hasn't happened by the time it's compiled
+                let module = self.module.prepare(&hotswap.new_module)?;
+                if self.module.canary_options.is_some() {
+                    return self.start_canary(module, claims);
+                }
+                let generation = self
+                    .interrupt
+                    .commit(|| self.module.install_version(module, claims))?;
+                self.refresh()?;
+                if let Some(grace) = self.module.health_grace {
+                    if !self.check_health(grace, None) {
+                        // Failed only because it was interrupted, and the multiplexer has
+                        // reported that the update was installed
+                        if self.interrupt.is_interrupted() {
+                            return Err(errors::new(errors::ErrorKind::GuestInterrupted));
+                        }
+                        let restored = self.module.rollback()?;
+                        self.refresh()?;
+                        error!(
+                            "HOT SWAP - Module generation {} failed its health check, rolled back to generation {}",
+                            generation, restored
+                        );
+                        return Ok(failure_event(
+                            500,
+                            "Live update failed its health check and was rolled back",
+                        ));
+                    }
+                }
+                info!("HOT SWAP - Success, module generation {}", generation);
                 Ok(Event {
                     success: true,
                     ..Default::default()
@@ -93,37 +719,285 @@ impl ModuleHost {
         }
     }
 
-    fn guest_call_fn(&self) -> Result<Func<(i32, i32), i32>> {
-        let f: Func<(i32, i32), i32> = self.instance.func(GUEST_CALL)?;
+    /// Starts trialling a verified live update alongside the current version, once it
+    /// has passed its health check
+    fn start_canary(&mut self, module: Module, claims: Claims) -> Result<Event> {
+        let serial = self
+            .interrupt
+            .commit(|| self.module.start_canary(module, claims))?;
+        if let Some(grace) = self.module.health_grace {
+            if let Some(canary) = self.module.canary() {
+                if !self.check_health(grace, Some(&*canary)) {
+                    if self.interrupt.is_interrupted() {
+                        return Err(errors::new(errors::ErrorKind::GuestInterrupted));
+                    }
+                    error!("HOT SWAP - Canary {} failed its health check", serial);
+                    self.module.abort_canary()?;
+                    return Ok(failure_event(
+                        500,
+                        "Live update failed its health check and was abandoned",
+                    ));
+                }
+            }
+        }
+        info!(
+            "HOT SWAP - Canary {} is now handling a share of commands",
+            serial
+        );
+        Ok(Event {
+            success: true,
+            ..Default::default()
+        })
+    }
+
+    /// Sends health checks to the guest, or to the given canary, until one succeeds or
+    /// the grace period runs out
+    fn check_health(&mut self, grace: Duration, canary: Option<&Canary>) -> bool {
+        let deadline = Instant::now() + grace;
+        let check = codec::core::HealthRequest { placeholder: true }
+            .as_command(HEALTH_CHECK_SOURCE, "guest");
+
+        loop {
+            let result = match canary {
+                Some(canary) => self.call_canary(canary, &check),
+                None => self.call(&check),
+            };
+            match result {
+                Ok(ref evt) if evt.success => return true,
+                Ok(evt) => warn!(
+                    "Health check failed: {}",
+                    evt.error
+                        .map_or("(no error)".to_string(), |e| e.description)
+                ),
+                Err(e) => warn!("Health check failed: {}", e),
+            }
+            // An interrupted host fails every check
+            if self.interrupt.is_interrupted() {
+                return false;
+            }
+            if Instant::now() + HEALTH_CHECK_INTERVAL > deadline {
+                return false;
+            }
+            thread::sleep(HEALTH_CHECK_INTERVAL);
+        }
+    }
+
+    /// Re-instantiates the guest if the shared module has been replaced since this
+    /// instance was created
+    fn refresh(&mut self) -> Result<()> {
+        if self.module.generation() != self.generation {
+            self.reinstantiate()?;
+        }
+        if let Some((serial, _)) = self.canary {
+            // The canary was promoted, aborted or superseded
+            if self.module.canary().map_or(true, |c| c.serial != serial) {
+                self.canary = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn reinstantiate(&mut self) -> Result<()> {
+        let (generation, instance) = self.module.instantiate(&self.interrupt)?;
+        self.generation = generation;
+        self.instance = instance;
+        Ok(())
+    }
+
+    fn guest_call_fn(&mut self, canary: bool) -> Result<Func<(i32, i32), i32>> {
+        let f: Func<(i32, i32), i32> = self.instance(canary).func(GUEST_CALL)?;
         Ok(f)
     }
 
     fn guest_free_fn(&self) -> Result<Func<(i32, i32)>> {
-        let f: Func<(i32, i32)> = self.instance.func(GUEST_FREE)?;
+        let f: Func<(i32, i32)> = self.instance.instance.func(GUEST_FREE)?;
         Ok(f)
     }
 
     pub fn get_vec_at_gp(&self, len: i32) -> Vec<u8> {
-        get_vec_from_wasm_gp(&self.instance, len)
+        get_vec_from_wasm_gp(&self.instance.instance, len)
     }
 }
 
-fn create_instance_from_buf(buf: &[u8]) -> Result<Instance> {
-    let import_object = imports! {
+fn import_object() -> ImportObject {
+    imports! {
         HOST_NAMESPACE => {
             HOST_CONSOLE_LOG => func!(console_log),
             HOST_THROW => func!(throw),
             HOST_CALL => func!(host_call),
         },
+        METERING_NAMESPACE => {
+            METERING_GAS => func!(gas),
+        },
+    }
+}
+
+fn compile_module(buf: &[u8], metered: bool) -> Result<Module> {
+    let result = if metered {
+        compile(&instrument(buf)?)
+    } else {
+        compile(buf)
     };
+    match result {
+        Ok(module) => Ok(module),
+        Err(e) => Err(errors::new(errors::ErrorKind::WasmMisc(e.into()))),
+    }
+}
 
-    match instantiate(&buf, &import_object) {
-        Ok(instance) => Ok(instance),
-        Err(e) => Err(errors::new(errors::ErrorKind::WasmMisc(e))),
+/// Injects a call to the host's metering hook at the start of every block, which is
+/// where an interrupted guest call is stopped
+fn instrument(buf: &[u8]) -> Result<Vec<u8>> {
+    let module: parity_wasm::elements::Module =
+        parity_wasm::deserialize_buffer(buf).map_err(instrumentation_error)?;
+    let rules = pwasm_utils::rules::Set::default();
+    let mut module = pwasm_utils::inject_gas_counter(module, &rules).map_err(|_| {
+        errors::new(errors::ErrorKind::Instrumentation(
+            "unable to inject metering".to_string(),
+        ))
+    })?;
+    // The hook's import is the last one, appended after the guest's own
+    let hook = module
+        .import_section_mut()
+        .and_then(|imports| imports.entries_mut().last_mut())
+        .filter(|entry| entry.module() == INJECTED_NAMESPACE && entry.field() == METERING_GAS);
+    match hook {
+        Some(entry) => *entry.module_mut() = METERING_NAMESPACE.to_string(),
+        None => {
+            return Err(errors::new(errors::ErrorKind::Instrumentation(
+                "metering hook import not found".to_string(),
+            )))
+        }
     }
+    parity_wasm::serialize(module).map_err(instrumentation_error)
 }
 
-fn is_live_update(cmd: &Command) -> bool {
+fn instrumentation_error(e: parity_wasm::elements::Error) -> errors::Error {
+    errors::new(errors::ErrorKind::Instrumentation(format!("{}", e)))
+}
+
+/// A directory of compiled modules. Loading a compiled artifact runs its machine code
+/// as is, so every artifact is stored with a digest keyed with a secret, and one whose
+/// digest doesn't match is recompiled rather than loaded. The directory and the files
+/// written to it are only accessible to the host's user. The key is read from a file
+/// given by the operator or, failing that, generated into the cache directory, in which
+/// case the cache is only as trustworthy as the directory itself
+pub struct ModuleCache {
+    dir: PathBuf,
+    key: Vec<u8>,
+}
+
+impl ModuleCache {
+    pub fn open(dir: &Path, key_file: Option<&Path>) -> Result<ModuleCache> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let key = match key_file {
+            Some(key_file) => fs::read(key_file)?,
+            None => generated_cache_key(&dir.join(CACHE_KEY_FILE))?,
+        };
+        if key.is_empty() {
+            return Err(errors::new(errors::ErrorKind::ModuleCache(
+                "empty cache key".to_string(),
+            )));
+        }
+        Ok(ModuleCache {
+            dir: dir.to_path_buf(),
+            // Brought down to a length BLAKE2b accepts as a key
+            key: blake2b_simd::blake2b(&key).as_bytes().to_vec(),
+        })
+    }
+
+    fn load(&self, path: &Path, name: &str) -> Result<Module> {
+        let buf = fs::read(path)?;
+        if buf.len() < CACHE_DIGEST_LENGTH
+            // Compared in constant time
+            || self.digest(name, &buf[CACHE_DIGEST_LENGTH..]) != buf[..CACHE_DIGEST_LENGTH]
+        {
+            return Err(errors::new(errors::ErrorKind::ModuleCache(format!(
+                "digest mismatch for {}",
+                path.display()
+            ))));
+        }
+        let artifact = Artifact::deserialize(&buf[CACHE_DIGEST_LENGTH..]).map_err(cache_error)?;
+        // Safe as far as the artifact is the one this host wrote, which the digest shows
+        let module = unsafe { wasmer_runtime_core::load_cache_with(artifact, default_compiler()) }
+            .map_err(cache_error)?;
+        Ok(module)
+    }
+
+    fn store(&self, path: &Path, name: &str, module: &Module) -> Result<()> {
+        let artifact = module.cache().map_err(cache_error)?;
+        let buf = artifact.serialize().map_err(cache_error)?;
+        let digest = self.digest(name, &buf);
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // The mode only applies to newly created files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(digest.as_bytes())?;
+        file.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Covers the artifact's name as well as its contents, so an artifact can't be passed
+    /// off as the compiled form of a different module
+    fn digest(&self, name: &str, artifact: &[u8]) -> blake2b_simd::Hash {
+        blake2b_simd::Params::new()
+            .hash_length(CACHE_DIGEST_LENGTH)
+            .key(&self.key)
+            .to_state()
+            .update(name.as_bytes())
+            .update(&[0])
+            .update(artifact)
+            .finalize()
+    }
+}
+
+/// Reads the key generated for a cache, generating it first if there isn't one yet
+fn generated_cache_key(path: &Path) -> Result<Vec<u8>> {
+    if path.exists() {
+        return Ok(fs::read(path)?);
+    }
+    let mut key = vec![0; CACHE_KEY_LENGTH];
+    fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(&key)?;
+    Ok(key)
+}
+
+fn cache_error(e: wasmer_runtime_core::cache::Error) -> errors::Error {
+    errors::new(errors::ErrorKind::ModuleCache(format!("{:?}", e)))
+}
+
+/// Surfaces a guest's `__throw` as its own error kind so the caller gets the
+/// guest's message rather than an opaque runtime error
+fn trap_error(e: error::RuntimeError) -> errors::Error {
+    match e {
+        error::RuntimeError::Error { data } => match data.downcast::<GuestPanic>() {
+            Ok(panic) => errors::new(errors::ErrorKind::GuestPanic(panic.0)),
+            Err(data) => match data.downcast::<Interrupted>() {
+                Ok(_) => errors::new(errors::ErrorKind::GuestInterrupted),
+                Err(data) => {
+                    errors::new(errors::ErrorKind::WasmRuntime(error::RuntimeError::Error {
+                        data,
+                    }))
+                }
+            },
+        },
+        e => e.into(),
+    }
+}
+
+pub(crate) fn is_live_update(cmd: &Command) -> bool {
     cmd.payload
         .as_ref()
         .map_or(false, |p| p.type_url == codec::core::TYPE_URL_LIVE_UPDATE)
@@ -216,14 +1090,43 @@ fn write_bytes_to_memory(memory: &Memory, ptr: i32, slice: &[u8]) {
 
 // -- Host Functions Follow --
 
-/// Invoked by the guest module when it wants to make a call to a capability
-fn host_call(ctx: &mut Ctx, ptr: i32, len: i32, retptr: i32) -> i32 {
+/// The context of the instance making the host call
+fn context(ctx: &Ctx) -> &GuestContext {
+    // Set by `GuestModule::instantiate_module` for every instance, and dropped after it
+    unsafe { &*(ctx.data as *const GuestContext) }
+}
+
+/// Raised by a host function to unwind a guest call that has been interrupted
+struct Interrupted;
+
+/// The metering hook injected into every block of the guest. Block costs aren't
+/// counted; the hook only gives the host a chance to stop the guest
+fn gas(ctx: &mut Ctx, _cost: i32) -> std::result::Result<(), Interrupted> {
+    if context(ctx).interrupt.is_interrupted() {
+        Err(Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Invoked by the guest module when it wants to make a call to a capability. An
+/// interrupted guest traps instead, so its caller's deadline really is the last moment
+/// it can reach a capability provider
+fn host_call(
+    ctx: &mut Ctx,
+    ptr: i32,
+    len: i32,
+    retptr: i32,
+) -> std::result::Result<i32, Interrupted> {
+    if context(ctx).interrupt.is_interrupted() {
+        return Err(Interrupted);
+    }
     let vec = get_vec_from_memory(&ctx.memory(0), ptr, len);
     let cmd = Command::decode(&vec).unwrap();
     info!("Guest module invoking host call for {}", cmd.target_cap);
 
     let result = {
-        let lock = CAPMAN.read().unwrap();
+        let lock = context(ctx).capabilities.read().unwrap();
         lock.call(&cmd)
     };
     let event = match result {
@@ -242,7 +1145,7 @@ fn host_call(ctx: &mut Ctx, ptr: i32, len: i32, retptr: i32) -> i32 {
     event.encode(&mut buf).unwrap();
     write_bytes_to_memory(&ctx.memory(0), retptr, &buf);
 
-    buf.len() as i32
+    Ok(buf.len() as i32)
 }
 
 fn console_log(ctx: &mut Ctx, ptr: i32, len: i32) {
@@ -251,7 +1154,149 @@ fn console_log(ctx: &mut Ctx, ptr: i32, len: i32) {
     info!("Wasm Guest: {}", std::str::from_utf8(&vec).unwrap());
 }
 
-fn throw(ctx: &mut Ctx, ptr: i32, b: i32) -> () {
-    println!("Module threw an exception!");
-    std::process::abort();
+/// Raised by `__throw` to unwind the guest call currently in progress
+struct GuestPanic(String);
+
+fn throw(ctx: &mut Ctx, ptr: i32, len: i32) -> std::result::Result<(), GuestPanic> {
+    let vec = get_vec_from_memory(&ctx.memory(0), ptr, len);
+    let msg = String::from_utf8_lossy(&vec).to_string();
+
+    error!("Wasm Guest threw an exception: {}", msg);
+    Err(GuestPanic(msg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parity_wasm::builder;
+    use parity_wasm::elements::{BlockType, Instruction::*, Instructions, Local, ValueType};
+
+    /// A module exporting `count(n)`, which loops `n` times and returns the number of
+    /// iterations. It can import a function of its own named like the metering hook
+    fn counting_module(import_gas: bool) -> Vec<u8> {
+        let mut module = builder::module();
+        if import_gas {
+            module = module
+                .import()
+                .module(INJECTED_NAMESPACE)
+                .field(METERING_GAS)
+                .external()
+                .func(0)
+                .build();
+        }
+        let module = module
+            .function()
+            .signature()
+            .with_param(ValueType::I32)
+            .with_return_type(Some(ValueType::I32))
+            .build()
+            .body()
+            .with_locals(vec![Local::new(1, ValueType::I32)])
+            .with_instructions(Instructions::new(vec![
+                Block(BlockType::NoResult),
+                Loop(BlockType::NoResult),
+                GetLocal(0),
+                I32Eqz,
+                BrIf(1),
+                GetLocal(0),
+                I32Const(1),
+                I32Sub,
+                SetLocal(0),
+                GetLocal(1),
+                I32Const(1),
+                I32Add,
+                SetLocal(1),
+                Br(0),
+                End,
+                End,
+                GetLocal(1),
+                End,
+            ]))
+            .build()
+            .build()
+            .export()
+            .field("count")
+            .internal()
+            .func(if import_gas { 1 } else { 0 })
+            .build()
+            .build();
+        parity_wasm::serialize(module).unwrap()
+    }
+
+    fn imports_of(buf: &[u8]) -> Vec<(String, String)> {
+        let module: parity_wasm::elements::Module = parity_wasm::deserialize_buffer(buf).unwrap();
+        module.import_section().map_or(Vec::new(), |imports| {
+            imports
+                .entries()
+                .iter()
+                .map(|entry| (entry.module().to_string(), entry.field().to_string()))
+                .collect()
+        })
+    }
+
+    fn hook() -> (String, String) {
+        (METERING_NAMESPACE.to_string(), METERING_GAS.to_string())
+    }
+
+    #[test]
+    fn metering_hook_is_imported_from_the_host_namespace() {
+        let buf = instrument(&counting_module(false)).unwrap();
+        assert_eq!(imports_of(&buf), vec![hook()]);
+    }
+
+    #[test]
+    fn metering_leaves_the_guests_own_imports_alone() {
+        let buf = instrument(&counting_module(true)).unwrap();
+        assert_eq!(
+            imports_of(&buf),
+            vec![
+                (INJECTED_NAMESPACE.to_string(), METERING_GAS.to_string()),
+                hook()
+            ]
+        );
+    }
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    /// Does the same work as the real metering hook, without an instance context
+    fn test_gas(_ctx: &mut Ctx, _cost: i32) -> std::result::Result<(), Interrupted> {
+        if STOP.load(Ordering::SeqCst) {
+            Err(Interrupted)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Compares a tight loop with and without metering. Run it with
+    /// `cargo test --release metering_overhead -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn metering_overhead() {
+        let buf = counting_module(false);
+        let iterations = 100_000_000;
+        let time = |metered| {
+            let module = compile_module(&buf, metered).unwrap();
+            let instance = module
+                .instantiate(&imports! {
+                    METERING_NAMESPACE => {
+                        METERING_GAS => func!(test_gas),
+                    },
+                })
+                .unwrap();
+            let count: Func<i32, i32> = instance.func("count").unwrap();
+            let start = Instant::now();
+            assert_eq!(count.call(iterations).unwrap(), iterations);
+            start.elapsed()
+        };
+
+        let unmetered = time(false);
+        let metered = time(true);
+        println!(
+            "{} iterations: {:?} unmetered, {:?} metered ({:.1}x)",
+            iterations,
+            unmetered,
+            metered,
+            metered.as_nanos() as f64 / unmetered.as_nanos() as f64
+        );
+    }
 }